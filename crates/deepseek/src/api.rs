@@ -1,6 +1,26 @@
+use crate::limiter::{Permit, RequestLimiter, Requester};
 use crate::types::*;
 
 use std::fmt::Write;
+
+/// Shown in place of an answer when DeepSeek sends none
+pub const NO_RESPONSE: &str = "DeepSeek didn't provide any valid response to your query.";
+
+/// An error status from the API, e.g. 401 for a wrong key or 402 when the balance has run out
+#[derive(Debug)]
+pub struct DeepSeekStatusError {
+    pub status: u16,
+    pub body: String,
+}
+
+impl std::fmt::Display for DeepSeekStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DeepSeek API returned status {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for DeepSeekStatusError {}
+
 fn report(mut err: &dyn std::error::Error) -> String {
     let mut s = format!("{}", err);
     while let Some(src) = err.source() {
@@ -10,47 +30,156 @@ fn report(mut err: &dyn std::error::Error) -> String {
     s
 }
 
+/// Told the model, usage and duration of every completion, including those given up on
+pub type UsageCallback = std::sync::Arc<dyn Fn(&DeepSeekModel, &DeepSeekUsage, std::time::Duration) + Send + Sync>;
+
+/// Passes the usage of a completion to `on_usage` once it ends, however it ends. When DeepSeek didn't report it,
+/// because the request was given up on or failed halfway, it is estimated from what was sent and received.
+struct UsageReport<'a> {
+    on_usage: Option<&'a UsageCallback>,
+    model: &'a DeepSeekModel,
+    messages: &'a [DeepSeekMessage],
+    started: std::time::Instant,
+    usage: Option<DeepSeekUsage>,
+    /// estimated tokens of the content and reasoning received so far
+    received: f64,
+}
+
+impl Drop for UsageReport<'_> {
+    fn drop(&mut self) {
+        if let Some(on_usage) = self.on_usage {
+            let usage = self.usage.take().unwrap_or_else(|| DeepSeekUsage::estimate(self.messages, self.received));
+            on_usage(self.model, &usage, self.started.elapsed());
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct DeepSeekAPI {
     pub token: String,
     pub timeout: u64,
     pub client: reqwest::Client,
+    /// completions wait here for their turn, if set
+    pub limiter: Option<std::sync::Arc<RequestLimiter>>,
+    pub requester: Requester,
+    pub on_usage: Option<UsageCallback>,
 }
 
 impl DeepSeekAPI {
-    pub async fn get_balance(&self) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
+    pub async fn get_balance(&self) -> Result<DeepSeekUserBalance, Box<dyn std::error::Error + Sync + Send>> {
         let response = self.client.get("https://api.deepseek.com/user/balance")
             .timeout(std::time::Duration::from_millis(self.timeout))
             .header("Accept", "application/json")
             .header("Authorization", format!("Bearer {}", self.token))
             .send()
             .await?;
-        let payload = serde_json::from_str::<DeepSeekUserBalance>(response.text().await?.as_str())?;
-        let mut ret = format!("Available: {}\n", payload.is_available);
-        for info in payload.balance_infos {
-            ret.push_str(&format!("  Currency: {}\n  Total Balance: {}\n\n", info.currency, info.total_balance));
-        }
-        Ok(ret)
+        Ok(serde_json::from_str::<DeepSeekUserBalance>(response.text().await?.as_str())?)
     }
     pub async fn single_message_dialog(&self, max_tokens: u64, query: String, model: DeepSeekModel) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
         self.single_message_dialog_with_system(max_tokens, query, String::new(), model).await
     }
     pub async fn single_message_dialog_with_system(&self, max_tokens: u64, query: String, system: String, model: DeepSeekModel) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
-        let model_name = match model {
-            DeepSeekModel::DeepSeekChat => "deepseek-chat",
-            DeepSeekModel::DeepSeekReasoner => "deepseek-reasoner",
+        Ok(self.single_message_dialog_with_reasoning(max_tokens, query, system, model).await?.content)
+    }
+    pub async fn single_message_dialog_with_reasoning(&self, max_tokens: u64, query: String, system: String, model: DeepSeekModel) -> Result<DeepSeekReply, Box<dyn std::error::Error + Sync + Send>> {
+        self.dialog(max_tokens, vec![DeepSeekMessage::system(system), DeepSeekMessage::user(query)], model).await
+    }
+    pub async fn dialog(&self, max_tokens: u64, messages: Vec<DeepSeekMessage>, model: DeepSeekModel) -> Result<DeepSeekReply, Box<dyn std::error::Error + Sync + Send>> {
+        let started = std::time::Instant::now();
+        let _permit = self.permit().await;
+        let response = self.post_completion(max_tokens, &messages, &model, false).await?;
+        let mut report = self.usage_report(&model, &messages, started);
+        let payload = serde_json::from_str::<DeepSeekChatResponse>(response.text().await?.as_str())?;
+        let mut ret = DeepSeekReply {
+            content: String::from(NO_RESPONSE),
+            reasoning_content: None,
+            finish_reason: String::new(),
+            usage: DeepSeekUsage::default(),
         };
+        if !payload.choices.is_empty() {
+            if let Some(text) = &payload.choices[0].message.content {
+                log::debug!("{}", text.as_str());
+                ret.content = text.as_str().to_string()
+            }
+            ret.reasoning_content = payload.choices[0].message.reasoning_content.to_owned().filter(|reasoning| !reasoning.trim().is_empty());
+            ret.finish_reason = payload.choices[0].finish_reason.to_owned();
+        }
+        report.received = estimate_tokens(&ret.content) + ret.reasoning_content.as_deref().map(estimate_tokens).unwrap_or_default();
+        report.usage = payload.usage.to_owned();
+        ret.usage = payload.usage.unwrap_or_default();
+        Ok(ret)
+    }
+    /// Like `dialog`, but streams the reply, passing each piece of its content and reasoning to `on_delta` as
+    /// it arrives. The content is left empty if there is none. Dropping the future aborts the request.
+    pub async fn dialog_stream(&self, max_tokens: u64, messages: Vec<DeepSeekMessage>, model: DeepSeekModel, mut on_delta: impl FnMut(&str, &str) + Send) -> Result<DeepSeekReply, Box<dyn std::error::Error + Sync + Send>> {
+        let started = std::time::Instant::now();
+        let _permit = self.permit().await;
+        let mut response = self.post_completion(max_tokens, &messages, &model, true).await?;
+        let mut report = self.usage_report(&model, &messages, started);
+        let mut ret = DeepSeekReply {
+            content: String::new(),
+            reasoning_content: None,
+            finish_reason: String::new(),
+            usage: DeepSeekUsage::default(),
+        };
+        let mut reasoning = String::new();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            // server-sent events, one `data: {...}` line per chunk of the reply
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line = buffer.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let Some(data) = line.trim().strip_prefix("data:").map(str::trim) else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    continue;
+                }
+                let chunk = serde_json::from_str::<DeepSeekStreamChunk>(data)?;
+                if let Some(usage) = chunk.usage {
+                    report.usage = Some(usage.to_owned());
+                    ret.usage = usage;
+                }
+                for choice in chunk.choices {
+                    let content = choice.delta.content.unwrap_or_default();
+                    let reasoning_content = choice.delta.reasoning_content.unwrap_or_default();
+                    if !content.is_empty() || !reasoning_content.is_empty() {
+                        report.received += estimate_tokens(&content) + estimate_tokens(&reasoning_content);
+                        ret.content.push_str(&content);
+                        reasoning.push_str(&reasoning_content);
+                        on_delta(&content, &reasoning_content);
+                    }
+                    if let Some(finish_reason) = choice.finish_reason {
+                        ret.finish_reason = finish_reason;
+                    }
+                }
+            }
+        }
+        ret.reasoning_content = Some(reasoning).filter(|reasoning| !reasoning.trim().is_empty());
+        Ok(ret)
+    }
+    fn usage_report<'a>(&'a self, model: &'a DeepSeekModel, messages: &'a [DeepSeekMessage], started: std::time::Instant) -> UsageReport<'a> {
+        UsageReport { on_usage: self.on_usage.as_ref(), model, messages, started, usage: None, received: 0.0 }
+    }
+    /// Waits for the turn of `requester`, to be held until the completion has been received
+    async fn permit(&self) -> Option<Permit<'_>> {
+        match &self.limiter {
+            Some(limiter) => limiter.acquire(&self.requester).await,
+            None => None,
+        }
+    }
+    async fn post_completion(&self, max_tokens: u64, messages: &[DeepSeekMessage], model: &DeepSeekModel, stream: bool) -> Result<reqwest::Response, Box<dyn std::error::Error + Sync + Send>> {
+        // streamed replies only report their usage when asked to
+        let stream_options = if stream { r#","stream_options": {"include_usage": true}"# } else { "" };
         let json_body = format!(r#"{{
             "model": "{}",
             "max_tokens": {},
-            "messages": [
-              {{"role": "system", "content": {}}},
-              {{"role": "user", "content": {}}}
-            ],
-            "stream": false
-        }}"#, model_name, max_tokens, serde_json::Value::String(system).to_string(), serde_json::Value::String(query).to_string());
-        eprintln!("{json_body}");
-        let response = match self.client.post("https://api.deepseek.com/chat/completions")
+            "messages": {},
+            "stream": {}{}
+        }}"#, model.name(), max_tokens, serde_json::to_string(messages)?, stream, stream_options);
+        log::debug!("{json_body}");
+        match self.client.post("https://api.deepseek.com/chat/completions")
             .timeout(std::time::Duration::from_millis(self.timeout))
             .header("User-Agent", "PostmanRuntime/7.43.0")
             .header("Cookie", "HWWAFSESID=a8e7a20b4e490a972ef; HWWAFSESTIME=1735732935007")
@@ -60,22 +189,16 @@ impl DeepSeekAPI {
             .body(json_body.to_owned())
             .send()
             .await {
-                Ok(response) => response,
+                Ok(response) if !response.status().is_success() => {
+                    let status = response.status().as_u16();
+                    let body = response.text().await.unwrap_or_default();
+                    Err(Box::new(DeepSeekStatusError { status, body }))
+                }
+                Ok(response) => Ok(response),
                 Err(e) => {
-                    eprintln!("ERROR: {}", report(&e));
-                    return Err(Box::new(e));
+                    log::error!("{}", report(&e));
+                    Err(Box::new(e))
                 }
-            };
-        let payload = serde_json::from_str::<DeepSeekChatResponse>(response.text().await?.as_str())?;
-        let mut ret = String::from("DeepSeek didn't provide any valid response to your query.");
-        if payload.choices.len() > 0 {
-            if let Some(text) = &payload.choices[0].message.content {
-                eprintln!("{}", text.as_str().to_string());
-                ret = text.as_str().to_string()
             }
-        }
-        Ok(ret)
     }
 }
-
-
@@ -0,0 +1,357 @@
+use crate::page::{self, Page};
+use crate::search::{SearchParams, SearchResult};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_CACHE_PATH: &str = "searchcache.json";
+
+/// Caching for the search pipeline, e.g. `[cache]` in the bot config. `ttl` and `save_interval` are in seconds.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub ttl: u64,
+    pub path: String,
+    pub save_interval: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl: 60 * 60 * 24,
+            path: String::from(DEFAULT_CACHE_PATH),
+            save_interval: 60,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn build(&self) -> Option<Arc<SearchCache>> {
+        if self.enabled {
+            Some(Arc::new(SearchCache::load(self.path.to_owned(), self.ttl)))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Entry<T> {
+    value: T,
+    stored_at: u64,
+}
+
+/// Everything that is persisted. Queries and search terms are stored normalized.
+#[derive(Serialize, Deserialize, Default)]
+struct CacheData {
+    #[serde(default)]
+    decisions: HashMap<String, Entry<bool>>,
+    #[serde(default)]
+    terms: HashMap<String, Entry<String>>,
+    #[serde(default)]
+    results: HashMap<String, Entry<Vec<SearchResult>>>,
+    #[serde(default)]
+    pages: HashMap<String, Entry<Page>>,
+    #[serde(default)]
+    summaries: HashMap<String, Entry<String>>,
+}
+
+#[derive(Default)]
+pub struct Counter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counter {
+    fn record(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Hit/miss counters since startup, one per cached stage.
+#[derive(Default)]
+pub struct CacheStats {
+    pub decisions: Counter,
+    pub terms: Counter,
+    pub results: Counter,
+    pub pages: Counter,
+    pub summaries: Counter,
+}
+
+impl CacheStats {
+    pub fn counters(&self) -> [(&'static str, &Counter); 5] {
+        [
+            ("decisions", &self.decisions),
+            ("search terms", &self.terms),
+            ("search results", &self.results),
+            ("pages", &self.pages),
+            ("summaries", &self.summaries),
+        ]
+    }
+}
+
+impl std::fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, counter) in self.counters() {
+            writeln!(f, "  {}: {} hits, {} misses", name, counter.hits(), counter.misses())?;
+        }
+        Ok(())
+    }
+}
+
+/// Lowercases and collapses whitespace, and drops trailing punctuation, so near-identical questions share entries.
+pub fn normalize(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+        .trim_end_matches(['?', '!', '.', '？', '！', '。', ' '])
+        .to_string()
+}
+
+fn results_key(term: &str, params: &SearchParams) -> String {
+    format!(
+        "{}\n{}\n{}\n{}\n{}",
+        normalize(term),
+        params.language.as_deref().unwrap_or_default(),
+        params.region.as_deref().unwrap_or_default(),
+        params.safe_search.as_str(),
+        params.count,
+    )
+}
+
+pub struct SearchCache {
+    ttl: u64,
+    path: String,
+    data: Mutex<CacheData>,
+    /// set when an entry is added, cleared when the cache is saved
+    dirty: AtomicBool,
+    /// held while writing the file
+    saving: Mutex<()>,
+    pub stats: CacheStats,
+}
+
+/// Reads the cache persisted at `path`. A cache that can't be read is set aside as `<path>.bad` rather than
+/// overwritten, and the bot starts with an empty one.
+fn load_data(path: &str) -> CacheData {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return CacheData::default(),
+        Err(e) => {
+            log::error!("Failed to read search cache {}: {}", path, e);
+            return CacheData::default();
+        }
+    };
+    match serde_json::from_str::<CacheData>(&content) {
+        Ok(data) => data,
+        Err(e) => {
+            log::error!("Failed to parse search cache {}, moving it to {}.bad: {}", path, path, e);
+            if let Err(e) = std::fs::rename(path, format!("{path}.bad")) {
+                log::error!("Failed to move search cache {}: {}", path, e);
+            }
+            CacheData::default()
+        }
+    }
+}
+
+/// Saves `cache` every `interval` seconds if anything was added to it
+pub async fn save_periodically(cache: Arc<SearchCache>, interval: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+    loop {
+        interval.tick().await;
+        if !cache.dirty.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        let cache = cache.clone();
+        match tokio::task::spawn_blocking(move || cache.save()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => log::error!("Failed to save search cache: {}", e),
+            Err(e) => log::error!("Failed to save search cache: {}", e),
+        }
+    }
+}
+
+impl SearchCache {
+    pub fn load(path: String, ttl: u64) -> Self {
+        let data = load_data(&path);
+        Self { ttl, path, data: Mutex::new(data), dirty: AtomicBool::new(false), saving: Mutex::new(()), stats: CacheStats::default() }
+    }
+    /// Drops expired entries and writes the cache to disk, through a temporary file so that it is never left
+    /// half-written. Blocks, so it is meant for `spawn_blocking`.
+    pub fn save(&self) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        let _saving = self.saving.lock().unwrap();
+        let content = {
+            let mut data = self.data.lock().unwrap();
+            let now = page::now();
+            let ttl = self.ttl;
+            let fresh = |stored_at: u64| stored_at + ttl > now;
+            data.decisions.retain(|_, entry| fresh(entry.stored_at));
+            data.terms.retain(|_, entry| fresh(entry.stored_at));
+            data.results.retain(|_, entry| fresh(entry.stored_at));
+            data.pages.retain(|_, entry| fresh(entry.stored_at));
+            data.summaries.retain(|_, entry| fresh(entry.stored_at));
+            serde_json::to_string(&*data)?
+        };
+        let temp = format!("{}.tmp", self.path);
+        std::fs::write(&temp, content)?;
+        std::fs::rename(&temp, &self.path)?;
+        Ok(())
+    }
+    fn get<T: Clone>(&self, counter: &Counter, select: impl FnOnce(&CacheData) -> &HashMap<String, Entry<T>>, key: &str) -> Option<T> {
+        let data = self.data.lock().unwrap();
+        let value = select(&data)
+            .get(key)
+            .filter(|entry| entry.stored_at + self.ttl > page::now())
+            .map(|entry| entry.value.clone());
+        counter.record(value.is_some());
+        value
+    }
+    fn put<T>(&self, select: impl FnOnce(&mut CacheData) -> &mut HashMap<String, Entry<T>>, key: String, value: T) {
+        let mut data = self.data.lock().unwrap();
+        select(&mut data).insert(key, Entry { value, stored_at: page::now() });
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+    /// Whether searching was deemed useful for `query`
+    pub fn decision(&self, query: &str) -> Option<bool> {
+        self.get(&self.stats.decisions, |data| &data.decisions, &normalize(query))
+    }
+    pub fn put_decision(&self, query: &str, decision: bool) {
+        self.put(|data| &mut data.decisions, normalize(query), decision)
+    }
+    /// The search term generated for `query`
+    pub fn term(&self, query: &str) -> Option<String> {
+        self.get(&self.stats.terms, |data| &data.terms, &normalize(query))
+    }
+    pub fn put_term(&self, query: &str, term: String) {
+        self.put(|data| &mut data.terms, normalize(query), term)
+    }
+    /// Raw backend results for a search term, searched for with `params`
+    pub fn results(&self, term: &str, params: &SearchParams) -> Option<Vec<SearchResult>> {
+        self.get(&self.stats.results, |data| &data.results, &results_key(term, params))
+    }
+    pub fn put_results(&self, term: &str, params: &SearchParams, results: Vec<SearchResult>) {
+        self.put(|data| &mut data.results, results_key(term, params), results)
+    }
+    /// The extract of the page at `url`, cut to `max_chars`
+    pub fn page(&self, url: &str, max_chars: usize) -> Option<Page> {
+        self.get(&self.stats.pages, |data| &data.pages, &format!("{}\n{}", max_chars, url))
+    }
+    pub fn put_page(&self, url: &str, max_chars: usize, page: Page) {
+        self.put(|data| &mut data.pages, format!("{}\n{}", max_chars, url), page)
+    }
+    /// The summary of the page at `url` with respect to a search term
+    pub fn summary(&self, term: &str, url: &str) -> Option<String> {
+        self.get(&self.stats.summaries, |data| &data.summaries, &format!("{}\n{}", normalize(term), url))
+    }
+    pub fn put_summary(&self, term: &str, url: &str, summary: String) {
+        self.put(|data| &mut data.summaries, format!("{}\n{}", normalize(term), url), summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A path of its own for each test, removed along with what saving leaves next to it
+    struct TempPath(String);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("deepseek-cache-test-{}-{}.json", std::process::id(), name));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            for path in [self.0.to_owned(), format!("{}.tmp", self.0), format!("{}.bad", self.0)] {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    #[test]
+    fn normalizes_near_identical_questions() {
+        assert_eq!(normalize("  What   is\nRust?? "), "what is rust");
+        assert_eq!(normalize("什么是 Rust？"), "什么是 rust");
+        let path = TempPath::new("normalize");
+        let cache = SearchCache::load(path.0.to_owned(), 60);
+        cache.put_decision("What is Rust?", true);
+        cache.put_term("what is rust", String::from("rust language"));
+        assert_eq!(cache.decision("what  is RUST"), Some(true));
+        assert_eq!(cache.term("What is Rust!"), Some(String::from("rust language")));
+        assert_eq!(cache.stats.decisions.hits(), 1);
+    }
+
+    #[test]
+    fn expires_entries_after_the_ttl() {
+        let path = TempPath::new("ttl");
+        let cache = SearchCache::load(path.0.to_owned(), 0);
+        cache.put_term("query", String::from("term"));
+        assert_eq!(cache.term("query"), None);
+        assert_eq!(cache.stats.terms.misses(), 1);
+    }
+
+    #[test]
+    fn keys_results_by_search_params() {
+        let path = TempPath::new("params");
+        let cache = SearchCache::load(path.0.to_owned(), 60);
+        let params = SearchParams::default();
+        let results = vec![SearchResult { url: String::from("https://example.com"), ..Default::default() }];
+        cache.put_results("Rust", &params, results);
+        assert_eq!(cache.results("rust", &params).map(|results| results.len()), Some(1));
+        for other in [
+            SearchParams { count: 10, ..SearchParams::default() },
+            SearchParams { language: Some(String::from("de")), ..SearchParams::default() },
+            SearchParams { safe_search: crate::search::SafeSearch::Off, ..SearchParams::default() },
+        ] {
+            assert!(cache.results("rust", &other).is_none());
+        }
+    }
+
+    #[test]
+    fn keeps_fresh_entries_across_saves() {
+        let path = TempPath::new("roundtrip");
+        let cache = SearchCache::load(path.0.to_owned(), 60);
+        cache.put_term("query", String::from("term"));
+        cache.put_summary("term", "https://example.com", String::from("summary"));
+        cache.save().unwrap();
+        assert!(!std::path::Path::new(&format!("{}.tmp", path.0)).exists());
+        let loaded = SearchCache::load(path.0.to_owned(), 60);
+        assert_eq!(loaded.term("query"), Some(String::from("term")));
+        assert_eq!(loaded.summary("TERM", "https://example.com"), Some(String::from("summary")));
+    }
+
+    #[test]
+    fn drops_expired_entries_when_saving() {
+        let path = TempPath::new("expired");
+        let cache = SearchCache::load(path.0.to_owned(), 0);
+        cache.put_term("query", String::from("term"));
+        cache.save().unwrap();
+        let loaded = SearchCache::load(path.0.to_owned(), 60);
+        assert_eq!(loaded.term("query"), None);
+    }
+
+    #[test]
+    fn sets_aside_a_cache_that_cannot_be_read() {
+        let path = TempPath::new("corrupt");
+        std::fs::write(&path.0, "{ not json").unwrap();
+        let cache = SearchCache::load(path.0.to_owned(), 60);
+        assert_eq!(cache.term("query"), None);
+        assert!(!std::path::Path::new(&path.0).exists());
+        assert_eq!(std::fs::read_to_string(format!("{}.bad", path.0)).unwrap(), "{ not json");
+    }
+}
@@ -1,3 +1,6 @@
 pub mod types;
 pub mod api;
+pub mod cache;
+pub mod limiter;
+pub mod page;
 pub mod search;
@@ -0,0 +1,251 @@
+use std::sync::{Arc, Mutex};
+
+/// Caps the DeepSeek requests in flight, e.g. `[limiter]` in the bot config. `max_concurrent` of 0 means no cap.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct LimiterConfig {
+    pub max_concurrent: usize,
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 8,
+        }
+    }
+}
+
+/// Whom a request is made for. Requests of `priority` requesters go before all others.
+#[derive(Clone, Default)]
+pub struct Requester {
+    pub user: u64,
+    pub priority: bool,
+    /// told how many requests are ahead while this one waits, and 0 once it is sent after waiting
+    pub on_position: Option<Arc<dyn Fn(usize) + Send + Sync>>,
+}
+
+struct Waiter {
+    /// in order of arrival
+    id: u64,
+    user: u64,
+    priority: bool,
+    /// the round the request is sent in, each user's requests taking one round after another
+    turn: u64,
+}
+
+#[derive(Default)]
+struct LimiterState {
+    running: usize,
+    waiting: Vec<Waiter>,
+    next_id: u64,
+    /// turn of the request sent last
+    round: u64,
+}
+
+impl LimiterState {
+    fn enqueue(&mut self, user: u64, priority: bool) -> u64 {
+        let turn = self.waiting.iter()
+            .filter(|waiter| waiter.user == user)
+            .map(|waiter| waiter.turn + 1)
+            .fold(self.round, u64::max);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.waiting.push(Waiter { id, user, priority, turn });
+        id
+    }
+    /// Ids of the waiting requests in the order they are to be sent: priority first, then taking turns between
+    /// users, so that one user's burst doesn't hold up everyone else
+    fn order(&self) -> Vec<u64> {
+        let mut order = self.waiting.iter().map(|waiter| (!waiter.priority, waiter.turn, waiter.id)).collect::<Vec<_>>();
+        order.sort();
+        order.into_iter().map(|(_, _, id)| id).collect()
+    }
+}
+
+/// A queue in front of the API, shared by all requests
+pub struct RequestLimiter {
+    max_concurrent: usize,
+    state: Mutex<LimiterState>,
+    changed: tokio::sync::Notify,
+}
+
+/// Holds a place among the requests in flight until dropped
+pub struct Permit<'a> {
+    limiter: &'a RequestLimiter,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().running -= 1;
+        self.limiter.changed.notify_waiters();
+    }
+}
+
+/// Takes a request out of the queue if it is given up on while waiting
+struct Waiting<'a> {
+    limiter: &'a RequestLimiter,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().waiting.retain(|waiter| waiter.id != self.id);
+        self.limiter.changed.notify_waiters();
+    }
+}
+
+impl RequestLimiter {
+    pub fn new(config: LimiterConfig) -> Self {
+        Self { max_concurrent: config.max_concurrent, state: Mutex::new(LimiterState::default()), changed: tokio::sync::Notify::new() }
+    }
+    /// Waits for the turn of `requester`, returning `None` if there is no cap
+    pub async fn acquire(&self, requester: &Requester) -> Option<Permit<'_>> {
+        if self.max_concurrent == 0 {
+            return None;
+        }
+        let id = self.state.lock().unwrap().enqueue(requester.user, requester.priority);
+        let waiting = Waiting { limiter: self, id };
+        // those queued behind it are told their new position
+        self.changed.notify_waiters();
+        let mut reported = None;
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let position = {
+                let mut state = self.state.lock().unwrap();
+                let position = state.order().iter().position(|&waiter| waiter == id).unwrap_or_default();
+                if position == 0 && state.running < self.max_concurrent {
+                    state.running += 1;
+                    state.round = state.waiting.iter().find(|waiter| waiter.id == id).map(|waiter| waiter.turn).unwrap_or(state.round);
+                    None
+                } else {
+                    // the requests in flight are ahead too, but only one of them has to finish
+                    Some(position + 1)
+                }
+            };
+            match position {
+                None => break,
+                Some(position) if reported != Some(position) => {
+                    if let Some(on_position) = &requester.on_position {
+                        on_position(position);
+                    }
+                    reported = Some(position);
+                }
+                _ => (),
+            }
+            changed.await;
+        }
+        drop(waiting);
+        if let (Some(on_position), Some(_)) = (&requester.on_position, reported) {
+            on_position(0);
+        }
+        Some(Permit { limiter: self })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lets the spawned tasks run until they wait again
+    async fn settle() {
+        for _ in 0..20 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn requester(user: u64, priority: bool) -> Requester {
+        Requester { user, priority, on_position: None }
+    }
+
+    /// Queues a request named `name`, which records its name once sent
+    fn queue(limiter: &Arc<RequestLimiter>, sent: &Arc<Mutex<Vec<&'static str>>>, name: &'static str, requester: Requester) -> tokio::task::JoinHandle<()> {
+        let (limiter, sent) = (limiter.clone(), sent.clone());
+        tokio::spawn(async move {
+            let _permit = limiter.acquire(&requester).await;
+            sent.lock().unwrap().push(name);
+        })
+    }
+
+    #[tokio::test]
+    async fn takes_turns_between_users_after_priority_requests() {
+        let limiter = Arc::new(RequestLimiter::new(LimiterConfig { max_concurrent: 1 }));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let running = limiter.acquire(&requester(0, false)).await;
+        let mut handles = Vec::new();
+        for (name, requester) in [
+            ("1a", requester(1, false)),
+            ("1b", requester(1, false)),
+            ("1c", requester(1, false)),
+            ("2a", requester(2, false)),
+            ("superuser", requester(9, true)),
+            ("3a", requester(3, false)),
+            ("2b", requester(2, false)),
+        ] {
+            handles.push(queue(&limiter, &sent, name, requester));
+            settle().await;
+        }
+        assert!(sent.lock().unwrap().is_empty());
+        drop(running);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*sent.lock().unwrap(), ["superuser", "1a", "2a", "3a", "1b", "2b", "1c"]);
+    }
+
+    #[tokio::test]
+    async fn reports_the_position_in_the_queue() {
+        let limiter = Arc::new(RequestLimiter::new(LimiterConfig { max_concurrent: 1 }));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let positions = Arc::new(Mutex::new(Vec::new()));
+        let running = limiter.acquire(&requester(0, false)).await;
+        let mut handles = vec![queue(&limiter, &sent, "1a", requester(1, false))];
+        settle().await;
+        let reported = positions.clone();
+        let on_position = Arc::new(move |position| reported.lock().unwrap().push(position));
+        handles.push(queue(&limiter, &sent, "1b", Requester { user: 1, priority: false, on_position: Some(on_position) }));
+        settle().await;
+        // the request in flight and 1a are ahead
+        assert_eq!(*positions.lock().unwrap(), [2]);
+        handles.push(queue(&limiter, &sent, "2a", requester(2, false)));
+        settle().await;
+        handles.push(queue(&limiter, &sent, "superuser", requester(9, true)));
+        settle().await;
+        // both went ahead of 1a's second request
+        assert_eq!(*positions.lock().unwrap(), [2, 3, 4]);
+        drop(running);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        let positions = positions.lock().unwrap();
+        assert_eq!(positions.last(), Some(&0));
+        assert!(positions[2..].windows(2).all(|pair| pair[1] < pair[0]), "{:?}", positions);
+        assert_eq!(sent.lock().unwrap().last(), Some(&"1b"));
+    }
+
+    #[tokio::test]
+    async fn drops_requests_given_up_on_from_the_queue() {
+        let limiter = Arc::new(RequestLimiter::new(LimiterConfig { max_concurrent: 1 }));
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let running = limiter.acquire(&requester(0, false)).await;
+        let given_up = queue(&limiter, &sent, "given up", requester(1, false));
+        settle().await;
+        let waiting = queue(&limiter, &sent, "waiting", requester(2, false));
+        settle().await;
+        given_up.abort();
+        settle().await;
+        drop(running);
+        waiting.await.unwrap();
+        assert_eq!(*sent.lock().unwrap(), ["waiting"]);
+        assert!(limiter.state.lock().unwrap().waiting.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lets_everything_through_without_a_cap() {
+        let limiter = RequestLimiter::new(LimiterConfig { max_concurrent: 0 });
+        let first = limiter.acquire(&requester(1, false)).await;
+        let second = limiter.acquire(&requester(1, false)).await;
+        assert!(first.is_none() && second.is_none());
+    }
+}
@@ -0,0 +1,274 @@
+//! Fetching web pages and extracting their readable text, shared by the search backends.
+
+use crate::cache::SearchCache;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+
+pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/109.0.0.0 Safari/537.36";
+const FETCH_TIMEOUT: u64 = 1000 * 5;
+const FETCH_ATTEMPTS: usize = 2;
+const MAX_REDIRECTS: usize = 5;
+/// Reading a page stops after this many bytes
+const MAX_PAGE_BYTES: usize = 2 * 1024 * 1024;
+/// Roughly 7000 tokens, same as the example search backend
+pub const MAX_PAGE_CHARS: usize = 7000 * 4;
+
+const SKIPPED_ELEMENTS: [&str; 7] = ["script", "style", "noscript", "template", "svg", "iframe", "head"];
+const BOILERPLATE_ELEMENTS: [&str; 5] = ["nav", "header", "footer", "aside", "form"];
+const MAIN_CONTENT_SELECTOR: &str = "article, main, [role=main]";
+/// An `<article>` or `<main>` shorter than this is taken for a teaser rather than the page's content
+const MIN_MAIN_CHARS: usize = 200;
+
+/// A downloaded page reduced to its readable parts.
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct Page {
+    pub title: String,
+    pub text: String,
+}
+
+/// Returns the contents of the `<title>` element, if any.
+pub fn extract_title(html: &str) -> String {
+    let document = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse("title").unwrap();
+    document.select(&selector)
+        .next()
+        .map(|title| title.text().collect::<Vec<_>>().join(" ").split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default()
+}
+
+/// Collects the visible text of an HTML document, dropping scripts and styles.
+pub fn extract_text(html: &str) -> String {
+    let document = scraper::Html::parse_document(html);
+    collect_text(document.root_element(), &SKIPPED_ELEMENTS)
+}
+
+/// Like `extract_text`, but keeps to the page's `<article>` or `<main>` when it has one, and otherwise drops
+/// navigation, headers, footers and sidebars.
+pub fn extract_main_text(html: &str) -> String {
+    let document = scraper::Html::parse_document(html);
+    let selector = scraper::Selector::parse(MAIN_CONTENT_SELECTOR).unwrap();
+    let main = document.select(&selector)
+        .map(|element| collect_text(element, &SKIPPED_ELEMENTS))
+        .max_by_key(|text| text.chars().count());
+    match main {
+        Some(text) if text.chars().count() >= MIN_MAIN_CHARS => text,
+        _ => collect_text(document.root_element(), &[&SKIPPED_ELEMENTS[..], &BOILERPLATE_ELEMENTS[..]].concat()),
+    }
+}
+
+fn collect_text(root: scraper::ElementRef, skipped_elements: &[&str]) -> String {
+    let mut text = String::new();
+    for node in root.descendants() {
+        let Some(fragment) = node.value().as_text() else {
+            continue;
+        };
+        let skipped = node.ancestors().any(|ancestor| {
+            ancestor.value().as_element().is_some_and(|element| skipped_elements.contains(&element.name()))
+        });
+        if skipped {
+            continue;
+        }
+        let fragment = fragment.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !fragment.is_empty() {
+            if !text.is_empty() {
+                text.push(' ');
+            }
+            text.push_str(&fragment);
+        }
+    }
+    text
+}
+
+/// Truncates `text` to at most `max_chars` characters without splitting a character.
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((index, _)) => text[..index].to_string(),
+        None => text.to_string(),
+    }
+}
+
+/// Downloads `url` and extracts its title and text, or returns `None` if the page can't be retrieved.
+pub async fn fetch_page(url: &str, max_chars: usize) -> Option<Page> {
+    for _ in 0..FETCH_ATTEMPTS {
+        match fetch_content(url).await {
+            Ok(Content::Html(html)) => return Some(Page {
+                title: extract_title(&html),
+                text: truncate_chars(&extract_main_text(&html), max_chars),
+            }),
+            Ok(Content::Text(text)) => return Some(Page {
+                title: String::new(),
+                text: truncate_chars(text.trim(), max_chars),
+            }),
+            Err(e) => log::error!("Failed to retrieve {url}: {e}"),
+        }
+    }
+    None
+}
+
+/// Like `fetch_page`, but serves and stores extracts in `cache` when given.
+pub async fn fetch_page_cached(url: &str, max_chars: usize, cache: Option<&SearchCache>) -> Option<Page> {
+    if let Some(page) = cache.and_then(|cache| cache.page(url, max_chars)) {
+        return Some(page);
+    }
+    let page = fetch_page(url, max_chars).await?;
+    if let Some(cache) = cache {
+        cache.put_page(url, max_chars, page.clone());
+    }
+    Some(page)
+}
+
+/// Seconds since the Unix epoch, used to timestamp fetched content.
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Whether `ip` is on the public internet, as opposed to loopback, private, link-local, unspecified or otherwise
+/// reserved ranges that pages must not be fetched from
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast()
+                || ip.is_multicast() || ip.is_documentation()
+                || a == 0
+                // shared address space, benchmarking and reserved
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 198 && (18..20).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+                // unique local and link-local
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Whether `url` is http or https, and not an address that `is_public` rules out. Host names are checked
+/// when they are resolved.
+fn is_allowed_url(url: &reqwest::Url) -> bool {
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    match url.host() {
+        Some(url::Host::Ipv4(ip)) => is_public(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public(IpAddr::V6(ip)),
+        Some(url::Host::Domain(domain)) => !domain.eq_ignore_ascii_case("localhost") && !domain.to_lowercase().ends_with(".localhost"),
+        None => false,
+    }
+}
+
+/// Resolves host names to their public addresses only, so that no connection is made to the bot's own network
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((name.as_str(), 0)).await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+            if addrs.is_empty() {
+                return Err(format!("{} doesn't resolve to a public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// The client pages are fetched with. Every redirect is checked like the original URL.
+fn page_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .no_proxy()
+            .dns_resolver(Arc::new(PublicResolver))
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if !is_allowed_url(attempt.url()) {
+                    let error = format!("redirected to a disallowed address: {}", attempt.url());
+                    attempt.error(error)
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .build()
+            .expect("the page client can be built")
+    })
+}
+
+enum Content {
+    Html(String),
+    Text(String),
+}
+
+/// Downloads an HTML or plain text page, reading at most `MAX_PAGE_BYTES`
+async fn fetch_content(url: &str) -> Result<Content, Box<dyn std::error::Error + Sync + Send>> {
+    let parsed = reqwest::Url::parse(url)?;
+    if !is_allowed_url(&parsed) {
+        return Err(format!("{} is not an allowed address", url).into());
+    }
+    let mut response = page_client().get(parsed)
+        .timeout(std::time::Duration::from_millis(FETCH_TIMEOUT))
+        .header("User-Agent", USER_AGENT)
+        .header("Accept", "text/html, application/xhtml+xml, text/plain")
+        .send()
+        .await?
+        .error_for_status()?;
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_lowercase())
+        .unwrap_or_default();
+    let html = match content_type.as_str() {
+        "text/html" | "application/xhtml+xml" => true,
+        "text/plain" => false,
+        _ => return Err(format!("unsupported content type {:?}", content_type).into()),
+    };
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_PAGE_BYTES {
+            body.truncate(MAX_PAGE_BYTES);
+            break;
+        }
+    }
+    let text = String::from_utf8_lossy(&body).into_owned();
+    Ok(if html { Content::Html(text) } else { Content::Text(text) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(url: &str) -> bool {
+        is_allowed_url(&reqwest::Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn rejects_internal_addresses() {
+        for url in [
+            "http://127.0.0.1/", "http://10.0.0.1/", "http://172.16.5.4/", "http://192.168.1.1/", "http://169.254.169.254/latest/meta-data/",
+            "http://0.0.0.0/", "http://100.64.0.1/", "http://[::1]/", "http://[fe80::1]/", "http://[fd00::1]/", "http://[::ffff:127.0.0.1]/",
+            "http://localhost:8080/", "http://api.localhost/", "file:///etc/passwd", "ftp://example.com/",
+        ] {
+            assert!(!allowed(url), "{url}");
+        }
+    }
+
+    #[test]
+    fn allows_public_addresses() {
+        for url in ["https://example.com/page", "http://93.184.216.34/", "https://[2606:4700::1111]/"] {
+            assert!(allowed(url), "{url}");
+        }
+    }
+}
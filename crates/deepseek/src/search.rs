@@ -1,53 +1,661 @@
 use crate::api;
+use crate::cache::SearchCache;
+use crate::page;
+use std::sync::Arc;
 
-const DETERMINE_PROMPT: &'static str = "Please first think deeply about if you are 100% sure about the definition of every word in the query (instead of guessing or \"seems to be\"), and then answer whether it would be beneficial to search the web in order to get a better understanding about the query or check your answer's correctness or not if you were to answer this query very accurately without any possible problem. Note that your knowledge database could be outdated. IT IS VERY IMPORTANT TO NOT ANSWERING THE USER'S ORIGINAL QUERY IN YOUR CURRENT ANSWER! If searching the web would help you answer user's query(even for only a little bit), answer yes without any additional characters. Otherwise (only when you are very certain about your knowledge), answer no.";
-const TERM_PROMPT: &'static str = "Provide a google search term based on search query provided below in less than 20 words";
-const SUMMARY_PROMPT: &'static str = "You are an AI assistant tasked with summarizing content relevant to '{}'. Please provide a concise summary.";
-const FINAL_PROMPT: &'static str = "The user provides a bunch of search results for search query {search_term}. \n{content}\nBased on on the search results provided by the user, provide a response to user's query. In addition, report it if there are significant inconsistency in search results. But if the answer from search results conflicts with your knowledge database, then your knowledge is outdated. PLEASE ANSWER THE QUERY IN THE SAME LANGUAGE THAT IT'S ASKED!";
-const SEARCH_API_HOST_BASE_URL: &'static str = "http://127.0.0.1:5000";
+const DETERMINE_PROMPT: &str = "Please first think deeply about if you are 100% sure about the definition of every word in the query (instead of guessing or \"seems to be\"), and then answer whether it would be beneficial to search the web in order to get a better understanding about the query or check your answer's correctness or not if you were to answer this query very accurately without any possible problem. Note that your knowledge database could be outdated. IT IS VERY IMPORTANT TO NOT ANSWERING THE USER'S ORIGINAL QUERY IN YOUR CURRENT ANSWER! If searching the web would help you answer user's query(even for only a little bit), answer yes without any additional characters. Otherwise (only when you are very certain about your knowledge), answer no.";
+const TERM_PROMPT: &str = "Provide a google search term based on search query provided below in less than 20 words";
+const SUMMARY_PROMPT: &str = "You are an AI assistant tasked with summarizing content relevant to '{}'. Please provide a concise summary.";
+const REDUCE_PROMPT: &str = "You are an AI assistant tasked with summarizing content relevant to '{}'. The user provides partial summaries of consecutive parts of one web page. Please combine them into one concise summary.";
+const FINAL_PROMPT: &str = "The user provides a bunch of search results for search query {search_term}. \n{content}\nBased on on the search results provided by the user, provide a response to user's query. Cite the search results you rely on by their numbers in square brackets, like [1] or [2][3], right after the statements they support. In addition, report it if there are significant inconsistency in search results. But if the answer from search results conflicts with your knowledge database, then your knowledge is outdated. PLEASE ANSWER THE QUERY IN THE SAME LANGUAGE THAT IT'S ASKED!";
+const SEARCH_API_HOST_BASE_URL: &str = "http://127.0.0.1:5000";
+const DUCKDUCKGO_HTML_URL: &str = "https://html.duckduckgo.com/html/";
+const MAX_QUERY_CHARS: usize = 400;
+
+/// One web search hit. `content` is the extracted text of the page, `fetched_at` is a Unix timestamp.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub struct SearchResult {
+    #[serde(default)]
+    pub url: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub snippet: String,
+    #[serde(default)]
+    pub content: String,
+    #[serde(default)]
+    pub fetched_at: u64,
+}
 
 /// deepseek-bot's search api format
+///
+/// `articles` is the legacy format, which only carries page text. Failures are reported as `{"error": "..."}`.
 #[derive(serde::Deserialize)]
 pub struct SearchResults {
+    #[serde(default)]
+    pub results: Vec<SearchResult>,
+    #[serde(default)]
     pub articles: Vec<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SafeSearch {
+    Off,
+    #[default]
+    Moderate,
+    Strict,
+}
+
+impl SafeSearch {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Moderate => "moderate",
+            Self::Strict => "strict",
+        }
+    }
+}
+
+/// Options passed to the backend with every query. `language` and `region` are codes like `en` and `us`.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct SearchParams {
+    pub language: Option<String>,
+    pub region: Option<String>,
+    pub safe_search: SafeSearch,
+    pub count: usize,
+}
+
+impl Default for SearchParams {
+    fn default() -> Self {
+        Self {
+            language: None,
+            region: None,
+            safe_search: SafeSearch::default(),
+            count: 5,
+        }
+    }
+}
+
+/// Cleans up a (usually model-generated) search term: drops control characters and surrounding quotes,
+/// collapses whitespace and limits the length. Encoding is left to the HTTP client.
+pub fn sanitize_query(query: &str) -> String {
+    let query = query.chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let query = query.trim_matches(['"', '\'', '“', '”', '`']).trim();
+    page::truncate_chars(query, MAX_QUERY_CHARS)
+}
+
+/// A source of web search results.
+#[async_trait::async_trait]
+pub trait SearchBackend: Send + Sync {
+    async fn search(&self, query: &str, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>>;
+}
+
+/// The HTTP contract served by `example/search_backend.py`.
+pub struct HttpBackend {
+    pub base_url: String,
+    pub client: reqwest::Client,
+}
+
+#[async_trait::async_trait]
+impl SearchBackend for HttpBackend {
+    async fn search(&self, query: &str, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+        let mut query_pairs = vec![
+            ("query", query.to_string()),
+            ("safe_search", params.safe_search.as_str().to_string()),
+            ("count", params.count.to_string()),
+        ];
+        if let Some(language) = &params.language {
+            query_pairs.push(("language", language.to_owned()));
+        }
+        if let Some(region) = &params.region {
+            query_pairs.push(("region", region.to_owned()));
+        }
+        let response = self.client.get(format!("{}/search", self.base_url.trim_end_matches('/')))
+            .query(&query_pairs)
+            .send()
+            .await?;
+        let status = response.status();
+        let payload = serde_json::from_str::<SearchResults>(response.text().await?.as_str())
+            .map_err(|e| format!("Invalid response from search backend ({status}): {e}"))?;
+        if let Some(error) = payload.error {
+            return Err(format!("Search backend returned an error ({status}): {error}").into());
+        }
+        if !status.is_success() {
+            return Err(format!("Search backend returned {status}").into());
+        }
+        let fetched_at = page::now();
+        let legacy = payload.articles.into_iter().map(|content| SearchResult { content, ..Default::default() });
+        Ok(payload.results.into_iter()
+            .chain(legacy)
+            .map(|result| SearchResult { fetched_at: if result.fetched_at == 0 { fetched_at } else { result.fetched_at }, ..result })
+            .take(params.count)
+            .collect())
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SearxngResult {
+    url: String,
+    #[serde(default)]
+    title: String,
+    #[serde(default)]
+    content: String,
+}
+
+/// ref: https://docs.searxng.org/dev/search_api.html
+#[derive(serde::Deserialize)]
+struct SearxngResults {
+    results: Vec<SearxngResult>,
+}
+
+/// A SearxNG-compatible JSON API. The instance must have the `json` format enabled.
+pub struct SearxngBackend {
+    pub base_url: String,
+    pub client: reqwest::Client,
+    pub cache: Option<Arc<SearchCache>>,
+}
+
+#[async_trait::async_trait]
+impl SearchBackend for SearxngBackend {
+    async fn search(&self, query: &str, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+        let safe_search = match params.safe_search {
+            SafeSearch::Off => "0",
+            SafeSearch::Moderate => "1",
+            SafeSearch::Strict => "2",
+        };
+        let mut query_pairs = vec![("q", query.to_string()), ("format", String::from("json")), ("safesearch", String::from(safe_search))];
+        match (&params.language, &params.region) {
+            (Some(language), Some(region)) => query_pairs.push(("language", format!("{}-{}", language, region.to_uppercase()))),
+            (Some(language), None) => query_pairs.push(("language", language.to_owned())),
+            _ => (),
+        }
+        let response = self.client.get(format!("{}/search", self.base_url.trim_end_matches('/')))
+            .query(&query_pairs)
+            .send()
+            .await?
+            .error_for_status()?;
+        let payload = serde_json::from_str::<SearxngResults>(response.text().await?.as_str())
+            .map_err(|e| format!("Invalid response from SearxNG: {e}"))?;
+        let hits = payload.results.into_iter()
+            .take(params.count)
+            .map(|result| SearchResult { url: result.url, title: result.title, snippet: result.content, ..Default::default() })
+            .collect::<Vec<_>>();
+        Ok(fetch_all(hits, self.cache.as_deref()).await)
+    }
+}
+
+/// Searches DuckDuckGo and extracts the result pages in-process, without any external service.
+pub struct NativeBackend {
+    pub client: reqwest::Client,
+    pub cache: Option<Arc<SearchCache>>,
+}
+
+/// Remove DuckDuckGo redirection from the link.
+fn clean_duckduckgo_link(link: &str) -> Option<String> {
+    let link = if link.starts_with("//") { format!("https:{link}") } else { link.to_string() };
+    let parsed = url::Url::parse(&link).ok()?;
+    if parsed.host_str().is_some_and(|host| host.ends_with("duckduckgo.com")) && parsed.path() == "/l/" {
+        return parsed.query_pairs().find(|(key, _)| key == "uddg").map(|(_, value)| value.into_owned());
+    }
+    Some(link)
+}
+
+#[async_trait::async_trait]
+impl SearchBackend for NativeBackend {
+    async fn search(&self, query: &str, params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+        // ref: https://duckduckgo.com/duckduckgo-help-pages/settings/params
+        let safe_search = match params.safe_search {
+            SafeSearch::Off => "-2",
+            SafeSearch::Moderate => "-1",
+            SafeSearch::Strict => "1",
+        };
+        let mut query_pairs = vec![("q", query.to_string()), ("kp", String::from(safe_search))];
+        if let Some(region) = &params.region {
+            let language = params.language.as_deref().unwrap_or("en");
+            query_pairs.push(("kl", format!("{}-{}", region.to_lowercase(), language)));
+        }
+        let html = self.client.get(DUCKDUCKGO_HTML_URL)
+            .query(&query_pairs)
+            .header("User-Agent", page::USER_AGENT)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let hits = {
+            let document = scraper::Html::parse_document(&html);
+            let result_selector = scraper::Selector::parse("div.result").unwrap();
+            let link_selector = scraper::Selector::parse("a.result__a").unwrap();
+            let snippet_selector = scraper::Selector::parse(".result__snippet").unwrap();
+            let text_of = |element: scraper::ElementRef| element.text().collect::<Vec<_>>().join("").split_whitespace().collect::<Vec<_>>().join(" ");
+            document.select(&result_selector)
+                .filter_map(|result| {
+                    let anchor = result.select(&link_selector).next()?;
+                    Some(SearchResult {
+                        url: clean_duckduckgo_link(anchor.value().attr("href")?)?,
+                        title: text_of(anchor),
+                        snippet: result.select(&snippet_selector).next().map(text_of).unwrap_or_default(),
+                        ..Default::default()
+                    })
+                })
+                .take(params.count)
+                .collect::<Vec<_>>()
+        };
+        Ok(fetch_all(hits, self.cache.as_deref()).await)
+    }
+}
+
+/// Serves a fixed list of results, for tests and offline development.
+pub struct StaticBackend {
+    pub results: Vec<SearchResult>,
+}
+
+#[async_trait::async_trait]
+impl SearchBackend for StaticBackend {
+    async fn search(&self, _query: &str, _params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(self.results.clone())
+    }
+}
+
+/// Downloads the page behind every hit, falling back to the snippet when a page can't be retrieved.
+async fn fetch_all(hits: Vec<SearchResult>, cache: Option<&SearchCache>) -> Vec<SearchResult> {
+    futures::future::join_all(hits.into_iter().map(|hit| async move {
+        let page = page::fetch_page_cached(&hit.url, page::MAX_PAGE_CHARS, cache).await;
+        let fetched_at = page::now();
+        match page {
+            Some(page) => SearchResult {
+                title: if hit.title.is_empty() { page.title } else { hit.title },
+                content: page.text,
+                fetched_at,
+                ..hit
+            },
+            None => SearchResult { content: hit.snippet.to_owned(), fetched_at, ..hit },
+        }
+    }))
+        .await
+        .into_iter()
+        .filter(|result| !result.content.is_empty())
+        .collect()
+}
+
+fn default_base_url() -> String {
+    String::from(SEARCH_API_HOST_BASE_URL)
+}
+
+/// Selects the search backend, e.g. `backend = "searxng"` in the `[search]` section of the bot config.
+#[derive(serde::Deserialize, Clone)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum SearchBackendConfig {
+    Http {
+        #[serde(default = "default_base_url")]
+        base_url: String,
+    },
+    Searxng {
+        base_url: String,
+    },
+    Native,
+}
+
+impl Default for SearchBackendConfig {
+    fn default() -> Self {
+        Self::Http { base_url: default_base_url() }
+    }
+}
+
+/// The `[search]` section of the bot config: a backend and the parameters passed to it.
+#[derive(serde::Deserialize, Clone, Default)]
+pub struct SearchConfig {
+    #[serde(flatten)]
+    pub backend: SearchBackendConfig,
+    #[serde(flatten)]
+    pub params: SearchParams,
+}
+
+impl SearchBackendConfig {
+    pub fn build(&self, client: reqwest::Client, cache: Option<Arc<SearchCache>>) -> Arc<dyn SearchBackend> {
+        match self {
+            Self::Http { base_url } => Arc::new(HttpBackend { base_url: base_url.to_owned(), client }),
+            Self::Searxng { base_url } => Arc::new(SearxngBackend { base_url: base_url.to_owned(), client, cache }),
+            Self::Native => Arc::new(NativeBackend { client, cache }),
+        }
+    }
 }
 
-async fn search(query: String, client: reqwest::Client) -> Result<Vec<String>, Box<dyn std::error::Error + Sync + Send>> {
-    let response = client.get(format!("{}/search?query={}", SEARCH_API_HOST_BASE_URL, query.replace("\"", ""))).send().await?;
-    let payload = serde_json::from_str::<SearchResults>(response.text().await?.as_str())?;
-    Ok(payload.articles)
+
+/// The outcome of `SearchDriver::search_and_summary`. `sources[n - 1]` is cited as `[n]` in the answer.
+pub struct SearchSummary {
+    pub system_prompt: String,
+    pub sources: Vec<SearchResult>,
 }
 
+/// Controls how search results are summarized, e.g. `[summary]` in the bot config.
+///
+/// Articles longer than `chunk_chars` are split into at most `max_chunks` chunks which are
+/// summarized separately and then combined. `article_timeout` limits each request made to summarize, in
+/// milliseconds, not counting the time it waits for its turn.
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+pub struct SummaryConfig {
+    pub concurrency: usize,
+    pub article_timeout: u64,
+    pub chunk_chars: usize,
+    pub max_chunks: usize,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 4,
+            article_timeout: 1000 * 60,
+            chunk_chars: 8000,
+            max_chunks: 4,
+        }
+    }
+}
+
+/// Splits `text` into chunks of at most `chunk_chars` characters, preferring to break at whitespace.
+fn split_chunks(text: &str, chunk_chars: usize, max_chunks: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() && chunks.len() < max_chunks {
+        let end = match rest.char_indices().nth(chunk_chars.max(1)) {
+            Some((index, _)) => rest[..index].rfind(char::is_whitespace).filter(|&space| space > 0).unwrap_or(index),
+            None => rest.len(),
+        };
+        chunks.push(rest[..end].to_string());
+        rest = rest[end..].trim_start();
+    }
+    chunks
+}
 
 /// ref: https://cookbook.openai.com/examples/third_party/web_search_with_google_api_bring_your_own_browser_tool
+#[derive(Clone)]
 pub struct SearchDriver {
     pub api: api::DeepSeekAPI,
+    pub backend: Arc<dyn SearchBackend>,
+    pub params: SearchParams,
+    pub summary: SummaryConfig,
+    pub cache: Option<Arc<SearchCache>>,
 }
 
 impl SearchDriver {
-    pub fn from(api: api::DeepSeekAPI) -> Self {
-        Self { api }
+    pub fn new(api: api::DeepSeekAPI, backend: Arc<dyn SearchBackend>, params: SearchParams, summary: SummaryConfig, cache: Option<Arc<SearchCache>>) -> Self {
+        Self { api, backend, params, summary, cache }
     }
     pub async fn determine(&self, query: String) -> Result<bool, Box<dyn std::error::Error + Sync + Send>> {
-        let res = self.api.single_message_dialog_with_system(20, query, String::from(DETERMINE_PROMPT), crate::types::DeepSeekModel::DeepSeekChat).await?.trim().to_string();
+        if let Some(decision) = self.cache.as_ref().and_then(|cache| cache.decision(&query)) {
+            return Ok(decision);
+        }
+        let res = self.api.single_message_dialog_with_system(20, query.to_owned(), String::from(DETERMINE_PROMPT), crate::types::DeepSeekModel::DeepSeekChat).await?.trim().to_string();
+        if let Some(cache) = &self.cache {
+            cache.put_decision(&query, res != "no");
+        }
         Ok(res != "no")
     }
     pub async fn generate_search_term(&self, query: String) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
-        self.api.single_message_dialog_with_system(20, query, TERM_PROMPT.to_string(), crate::types::DeepSeekModel::DeepSeekChat).await
+        if let Some(term) = self.cache.as_ref().and_then(|cache| cache.term(&query)) {
+            return Ok(term);
+        }
+        let term = sanitize_query(&self.api.single_message_dialog_with_system(20, query.to_owned(), TERM_PROMPT.to_string(), crate::types::DeepSeekModel::DeepSeekChat).await?);
+        if term.is_empty() {
+            return Err("The generated search term is empty".into());
+        }
+        if let Some(cache) = &self.cache {
+            cache.put_term(&query, term.to_owned());
+        }
+        Ok(term)
     }
-    /// Returns a system prompt
-    pub async fn search_and_summary(&self, query: String) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
+    async fn search(&self, term: &str) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+        if let Some(results) = self.cache.as_ref().and_then(|cache| cache.results(term, &self.params)) {
+            return Ok(results);
+        }
+        let results = self.backend.search(term, &self.params).await?;
+        if let Some(cache) = self.cache.as_ref().filter(|_| !results.is_empty()) {
+            cache.put_results(term, &self.params, results.clone());
+        }
+        Ok(results)
+    }
+    /// Returns a system prompt along with the sources it cites
+    pub async fn search_and_summary(&self, query: String) -> Result<SearchSummary, Box<dyn std::error::Error + Sync + Send>> {
         let term = self.generate_search_term(query.to_owned()).await?;
-        let articles = search(term.to_owned(), self.api.client.clone()).await?;
+        let results = self.search(&term).await?;
+        let permits = tokio::sync::Semaphore::new(self.summary.concurrency.max(1));
+        let summaries = futures::future::join_all(results.iter().map(|result| self.summarize_result(&term, result, &permits))).await;
         let mut summarized_content = String::new();
-        for (index, article) in articles.into_iter().enumerate() {
-            if let Ok(summary) = self.api.single_message_dialog_with_system(100, article, SUMMARY_PROMPT.replace("{}", term.to_owned().as_str()), crate::types::DeepSeekModel::DeepSeekChat).await {
-                summarized_content.push_str(format!("Search order: {index}\nSummary: {summary}\n--------------------------------------------------------------------------------\n").as_str());
+        let mut sources = Vec::new();
+        for (result, summary) in results.into_iter().zip(summaries) {
+            let summary = match summary {
+                Ok(summary) => summary,
+                Err(e) => {
+                    log::error!("Failed to summarize {}: {}", result.url, e);
+                    continue;
+                }
+            };
+            sources.push(result);
+            let result = &sources[sources.len() - 1];
+            summarized_content.push_str(format!("[{}] {}\nURL: {}\nSummary: {summary}\n--------------------------------------------------------------------------------\n", sources.len(), result.title, result.url).as_str());
+        }
+        Ok(SearchSummary {
+            system_prompt: FINAL_PROMPT
+                .replace("{search_term}", &term)
+                .replace("{content}", &summarized_content),
+            sources,
+        })
+    }
+    async fn summarize_result(&self, term: &str, result: &SearchResult, permits: &tokio::sync::Semaphore) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
+        let cache = self.cache.as_ref().filter(|_| !result.url.is_empty());
+        if let Some(summary) = cache.and_then(|cache| cache.summary(term, &result.url)) {
+            return Ok(summary);
+        }
+        let summary = self.summarize_article(term, &result.content, permits).await?;
+        if let Some(cache) = cache {
+            cache.put_summary(term, &result.url, summary.to_owned());
+        }
+        Ok(summary)
+    }
+    /// Summarizes one article, map-reducing over chunks when it's too long. Fails only if every chunk fails.
+    async fn summarize_article(&self, term: &str, content: &str, permits: &tokio::sync::Semaphore) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
+        let chunks = split_chunks(content, self.summary.chunk_chars, self.summary.max_chunks);
+        let system = SUMMARY_PROMPT.replace("{}", term);
+        let partials = futures::future::join_all(chunks.into_iter().map(|chunk| self.summarize_chunk(chunk, system.to_owned(), permits))).await;
+        let mut error = None;
+        let mut summaries = Vec::new();
+        for partial in partials {
+            match partial {
+                Ok(summary) => summaries.push(summary),
+                Err(e) => error = Some(e),
+            }
+        }
+        match summaries.len() {
+            0 => Err(error.unwrap_or_else(|| "article is empty".into())),
+            1 => Ok(summaries.remove(0)),
+            _ => self.summarize_chunk(summaries.join("\n\n"), REDUCE_PROMPT.replace("{}", term), permits).await,
+        }
+    }
+    async fn summarize_chunk(&self, chunk: String, system: String, permits: &tokio::sync::Semaphore) -> Result<String, Box<dyn std::error::Error + Sync + Send>> {
+        let _permit = permits.acquire().await?;
+        // the request timeout only starts once the limiter lets the request through
+        let api = api::DeepSeekAPI { timeout: self.summary.article_timeout.min(self.api.timeout), ..self.api.clone() };
+        api.single_message_dialog_with_system(100, chunk, system, crate::types::DeepSeekModel::DeepSeekChat).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fails every search, like a backend that is down
+    struct FailingBackend;
+
+    #[async_trait::async_trait]
+    impl SearchBackend for FailingBackend {
+        async fn search(&self, _query: &str, _params: &SearchParams) -> Result<Vec<SearchResult>, Box<dyn std::error::Error + Sync + Send>> {
+            Err("backend is down".into())
+        }
+    }
+
+    /// An API whose requests all fail at once, through a proxy nobody listens on
+    fn offline_api() -> api::DeepSeekAPI {
+        api::DeepSeekAPI {
+            token: String::from("test"),
+            timeout: 1000,
+            client: reqwest::Client::builder().proxy(reqwest::Proxy::all("http://127.0.0.1:1").unwrap()).build().unwrap(),
+            limiter: None,
+            requester: Default::default(),
+            on_usage: None,
+        }
+    }
+
+    fn result(title: &str) -> SearchResult {
+        SearchResult {
+            url: format!("https://example.com/{}", title.to_lowercase()),
+            title: title.to_string(),
+            content: format!("The page about {title}."),
+            ..Default::default()
+        }
+    }
+
+    /// A cache at a path of its own that is never saved, so tests don't share entries
+    fn test_cache(name: &str) -> Arc<SearchCache> {
+        let path = std::env::temp_dir().join(format!("deepseek-search-test-{}-{}.json", std::process::id(), name));
+        Arc::new(SearchCache::load(path.to_string_lossy().into_owned(), 60))
+    }
+
+    fn driver(backend: Arc<dyn SearchBackend>, cache: Arc<SearchCache>) -> SearchDriver {
+        SearchDriver::new(offline_api(), backend, SearchParams::default(), SummaryConfig::default(), Some(cache))
+    }
+
+    /// Serves `body` with `status` to one request on a local port, returning the backend's base URL and the target
+    /// the request asked for
+    async fn serve_once(status: &'static str, body: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let read = stream.read(&mut buffer).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
             }
+            let response = format!("HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len());
+            stream.write_all(response.as_bytes()).await.unwrap();
+            let request = String::from_utf8_lossy(&request).into_owned();
+            request.split_whitespace().nth(1).unwrap_or_default().to_string()
+        });
+        (base_url, handle)
+    }
+
+    fn http_backend(base_url: String) -> HttpBackend {
+        HttpBackend { base_url, client: reqwest::Client::builder().no_proxy().build().unwrap() }
+    }
+
+    #[test]
+    fn sanitizes_generated_queries() {
+        assert_eq!(sanitize_query("  \"rust  async\n\tbook\"  "), "rust async book");
+        assert_eq!(sanitize_query("“C++ & C#”"), "C++ & C#");
+        assert_eq!(sanitize_query("`東京 天気`"), "東京 天気");
+        assert_eq!(sanitize_query("a\u{0}b"), "a b");
+        assert_eq!(sanitize_query(&"x".repeat(1000)).chars().count(), MAX_QUERY_CHARS);
+    }
+
+    #[tokio::test]
+    async fn encodes_queries_for_the_http_backend() {
+        let (base_url, request) = serve_once("200 OK", r#"{"results": [{"url": "https://example.com", "title": "Example", "content": "text"}]}"#).await;
+        let query = "C++ & C# 東京?a=b";
+        let params = SearchParams { language: Some(String::from("ja")), ..SearchParams::default() };
+        let results = http_backend(base_url).search(query, &params).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].fetched_at > 0);
+        let target = request.await.unwrap();
+        assert!(target.starts_with("/search?"), "{target}");
+        assert!(!target.contains('#') && !target.contains(' ') && target.is_ascii(), "{target}");
+        let url = url::Url::parse(&format!("http://localhost{target}")).unwrap();
+        let pairs = url.query_pairs().into_owned().collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(pairs["query"], query);
+        assert_eq!(pairs["language"], "ja");
+        assert_eq!(pairs["safe_search"], "moderate");
+        assert_eq!(pairs["count"], "5");
+    }
+
+    #[tokio::test]
+    async fn reads_legacy_articles_up_to_the_count() {
+        let (base_url, _) = serve_once("200 OK", r#"{"articles": ["one", "two", "three"]}"#).await;
+        let params = SearchParams { count: 2, ..SearchParams::default() };
+        let results = http_backend(base_url).search("query", &params).await.unwrap();
+        assert_eq!(results.iter().map(|result| result.content.as_str()).collect::<Vec<_>>(), ["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn returns_backend_errors() {
+        let (base_url, _) = serve_once("500 Internal Server Error", r#"{"error": "rate limited"}"#).await;
+        let e = http_backend(base_url).search("query", &SearchParams::default()).await.err().unwrap();
+        assert!(e.to_string().contains("rate limited"), "{e}");
+        // an error is an error even with a successful status
+        let (base_url, _) = serve_once("200 OK", r#"{"error": "no results"}"#).await;
+        assert!(http_backend(base_url).search("query", &SearchParams::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_responses() {
+        let (base_url, _) = serve_once("200 OK", "<html>not json</html>").await;
+        let e = http_backend(base_url).search("query", &SearchParams::default()).await.err().unwrap();
+        assert!(e.to_string().contains("Invalid response"), "{e}");
+        let (base_url, _) = serve_once("200 OK", r#"{"results": "not a list"}"#).await;
+        assert!(http_backend(base_url).search("query", &SearchParams::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn cites_summarized_results_in_backend_order() {
+        let cache = test_cache("order");
+        cache.put_term("What is B?", String::from("b term"));
+        for title in ["A", "B", "C"] {
+            cache.put_summary("b term", &result(title).url, format!("Summary of {title}"));
+        }
+        let backend = Arc::new(StaticBackend { results: vec![result("A"), result("B"), result("C")] });
+        let summary = driver(backend, cache).search_and_summary(String::from("What is B?")).await.unwrap();
+        assert_eq!(summary.sources.iter().map(|source| source.title.as_str()).collect::<Vec<_>>(), ["A", "B", "C"]);
+        let a = summary.system_prompt.find("[1] A\nURL: https://example.com/a\nSummary: Summary of A").unwrap();
+        let b = summary.system_prompt.find("[2] B\nURL: https://example.com/b\nSummary: Summary of B").unwrap();
+        let c = summary.system_prompt.find("[3] C\nURL: https://example.com/c\nSummary: Summary of C").unwrap();
+        assert!(a < b && b < c);
+        assert!(summary.system_prompt.contains("search query b term"));
+    }
+
+    #[tokio::test]
+    async fn leaves_out_results_that_fail_to_summarize() {
+        let cache = test_cache("partial");
+        cache.put_term("What is B?", String::from("b term"));
+        // B isn't cached, so it has to be summarized by the API, which fails
+        for title in ["A", "C"] {
+            cache.put_summary("b term", &result(title).url, format!("Summary of {title}"));
         }
-        Ok(FINAL_PROMPT
-            .replace("{search_term}", &term)
-            .replace("{content}", &summarized_content)
-        )
+        let backend = Arc::new(StaticBackend { results: vec![result("A"), result("B"), result("C")] });
+        let summary = driver(backend, cache).search_and_summary(String::from("What is B?")).await.unwrap();
+        assert_eq!(summary.sources.iter().map(|source| source.title.as_str()).collect::<Vec<_>>(), ["A", "C"]);
+        assert!(summary.system_prompt.contains("[1] A\n"));
+        assert!(summary.system_prompt.contains("[2] C\n"));
+        assert!(!summary.system_prompt.contains("Summary of B"));
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_backend_fails() {
+        let cache = test_cache("failing");
+        cache.put_term("What is B?", String::from("b term"));
+        let result = driver(Arc::new(FailingBackend), cache).search_and_summary(String::from("What is B?")).await;
+        assert!(result.is_err());
     }
 }
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct DeepSeekCompletionProbabilityTop {
@@ -35,6 +35,8 @@ pub struct DeepSeekCompletionMessageToolCall {
 #[derive(Deserialize)]
 pub struct DeepSeekCompletionMessage {
     pub content: Option<String>,
+    /// chain of thought, only returned by deepseek-reasoner
+    pub reasoning_content: Option<String>,
     pub tool_calls: Option<Vec<DeepSeekCompletionMessageToolCall>>,
     pub role: String,
 }
@@ -47,6 +49,44 @@ pub struct DeepSeekCompletionChoice {
     pub logprobs: Option<DeepSeekCompletionProbability>,
 }
 
+/// Tokens used by a completion. The cache fields tell how much of the prompt was found in DeepSeek's context cache.
+#[derive(Deserialize, Clone, Default)]
+pub struct DeepSeekUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    #[serde(default)]
+    pub prompt_cache_hit_tokens: u64,
+    #[serde(default)]
+    pub prompt_cache_miss_tokens: u64,
+}
+
+/// Rough number of tokens in `text`, going by DeepSeek's figures of about 0.3 tokens per English character and
+/// 0.6 per Chinese one
+/// ref: https://api-docs.deepseek.com/quick_start/token_usage
+pub fn estimate_tokens(text: &str) -> f64 {
+    text.chars().map(|c| if c.is_ascii() { 0.3 } else { 0.6 }).sum()
+}
+
+impl DeepSeekUsage {
+    /// Usage of a completion that ended before DeepSeek reported it, estimated from the prompt and the number of
+    /// tokens received. None of the prompt is taken to be cached.
+    pub fn estimate(messages: &[DeepSeekMessage], completion_tokens: f64) -> Self {
+        let prompt_tokens = messages.iter().map(|message| estimate_tokens(&message.content)).sum::<f64>().ceil() as u64;
+        Self {
+            prompt_tokens,
+            completion_tokens: completion_tokens.ceil() as u64,
+            prompt_cache_hit_tokens: 0,
+            prompt_cache_miss_tokens: prompt_tokens,
+        }
+    }
+    pub fn add(&mut self, other: &Self) {
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.prompt_cache_hit_tokens += other.prompt_cache_hit_tokens;
+        self.prompt_cache_miss_tokens += other.prompt_cache_miss_tokens;
+    }
+}
+
 /// ref: https://api-docs.deepseek.com/api/create-chat-completion
 #[derive(Deserialize)]
 pub struct DeepSeekChatResponse {
@@ -56,6 +96,26 @@ pub struct DeepSeekChatResponse {
     pub model: String,
     pub system_fingerprint: String,
     pub object: String,
+    pub usage: Option<DeepSeekUsage>,
+}
+
+#[derive(Deserialize)]
+pub struct DeepSeekStreamDelta {
+    pub content: Option<String>,
+    pub reasoning_content: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DeepSeekStreamChoice {
+    pub delta: DeepSeekStreamDelta,
+    pub finish_reason: Option<String>,
+}
+
+/// One event of a streamed reply. The last one has no choices, only the usage.
+#[derive(Deserialize)]
+pub struct DeepSeekStreamChunk {
+    pub choices: Vec<DeepSeekStreamChoice>,
+    pub usage: Option<DeepSeekUsage>,
 }
 
 #[derive(Deserialize)]
@@ -72,9 +132,66 @@ pub struct DeepSeekUserBalance {
     pub balance_infos: Vec<DeepSeekUserBalanceInfo>,
 }
 
+impl DeepSeekUserBalance {
+    /// The total balance in `currency`, if the account has one
+    pub fn total(&self, currency: &str) -> Option<f64> {
+        self.balance_infos.iter()
+            .find(|info| info.currency == currency)
+            .and_then(|info| info.total_balance.parse::<f64>().ok())
+    }
+}
+
+impl std::fmt::Display for DeepSeekUserBalance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Available: {}", self.is_available)?;
+        for info in &self.balance_infos {
+            write!(f, "  Currency: {}\n  Total Balance: {}\n\n", info.currency, info.total_balance)?;
+        }
+        Ok(())
+    }
+}
+
+/// A message of a dialog. `role` is one of system, user and assistant.
+#[derive(Serialize, Clone)]
+pub struct DeepSeekMessage {
+    pub role: String,
+    pub content: String,
+}
+
+impl DeepSeekMessage {
+    pub fn system(content: String) -> Self {
+        Self { role: String::from("system"), content }
+    }
+    pub fn user(content: String) -> Self {
+        Self { role: String::from("user"), content }
+    }
+    pub fn assistant(content: String) -> Self {
+        Self { role: String::from("assistant"), content }
+    }
+}
+
+/// The answer to a dialog, along with the reasoning behind it if the model shares it
+pub struct DeepSeekReply {
+    pub content: String,
+    pub reasoning_content: Option<String>,
+    /// why the model stopped, e.g. stop, or length when it ran out of tokens
+    pub finish_reason: String,
+    pub usage: DeepSeekUsage,
+}
+
 #[derive(Clone)]
 pub enum DeepSeekModel {
     DeepSeekChat,
     DeepSeekReasoner,
 }
 
+impl DeepSeekModel {
+    /// The name the API knows the model by
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeepSeekModel::DeepSeekChat => "deepseek-chat",
+            DeepSeekModel::DeepSeekReasoner => "deepseek-reasoner",
+        }
+    }
+}
+
@@ -3,6 +3,7 @@ from bs4 import BeautifulSoup
 from flask import Flask, request, jsonify
 from search_engine_parser.core.engines.duckduckgo import Search
 import asyncio
+import time
 from urllib.parse import urlparse, parse_qs, unquote
 
 app = Flask(__name__)
@@ -19,6 +20,7 @@ def clean_duckduckgo_link(link):
     return link
 
 def retrieve_content(url, max_tokens=7000):
+    """Return the (title, text) of a page, or None if it can't be fetched."""
     print(f'Fetching: {url}')
     for _ in range(2):
         try:
@@ -27,18 +29,26 @@ def retrieve_content(url, max_tokens=7000):
             response.raise_for_status()
 
             soup = BeautifulSoup(response.content, 'html.parser')
+            title = soup.title.get_text(strip=True) if soup.title else ''
             for script_or_style in soup(['script', 'style']):
                 script_or_style.decompose()
 
             text = soup.get_text(separator=' ', strip=True)
             characters = max_tokens * 4  # Approximate conversion
             text = text[:characters]
-            return text
+            return title, text
         except requests.exceptions.RequestException as e:
             print(f"Failed to retrieve {url}: {e}")
-    return ''
+    return None
 
-def run_async_search(query):
+def build_result(link, title, snippet):
+    page = retrieve_content(link)
+    if page is None:
+        return {"url": link, "title": title, "snippet": snippet, "content": snippet, "fetched_at": int(time.time())}
+    page_title, text = page
+    return {"url": link, "title": title or page_title, "snippet": snippet, "content": text, "fetched_at": int(time.time())}
+
+def run_async_search(query, count=5):
     # Create a new event loop for the search
     print(f'Searching {query}')
     loop = asyncio.new_event_loop()
@@ -48,24 +58,32 @@ def run_async_search(query):
         results = Search().search(query)
         # Clean up DuckDuckGo links
         cleaned_links = [clean_duckduckgo_link(link) for link in results['links']]
-        # print(list(map(retrieve_content, cleaned_links[:5])))
-        return list(map(retrieve_content, cleaned_links[:5]))
+        titles = results['titles'] or [''] * len(cleaned_links)
+        snippets = results['descriptions'] or [''] * len(cleaned_links)
+        hits = list(zip(cleaned_links, titles, snippets))[:count]
+        return [build_result(link, title, snippet) for link, title, snippet in hits]
     finally:
         loop.close()
 
 @app.route('/search', methods=['GET'])
 def search():
     # Get the query parameter from the request
+    # language, region and safe_search are also sent by the bot, but this backend ignores them
     query = request.args.get('query')
 
     if not query:
         return jsonify({"error": "Query parameter is required"}), 400
 
+    try:
+        count = int(request.args.get('count', 5))
+    except ValueError:
+        return jsonify({"error": "count must be an integer"}), 400
+
     try:
         # Run the search in a synchronous context
-        links = run_async_search(query)
-        # Return the links as a JSON object
-        return jsonify({"articles": links})
+        results = run_async_search(query, count)
+        # Return the results as a JSON object
+        return jsonify({"results": [result for result in results if result["content"]]})
     except Exception as e:
         return jsonify({"error": str(e)}), 500
 
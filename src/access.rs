@@ -0,0 +1,114 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::Mutex;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup, User};
+
+const ACCESS_REQUESTS_PATH: &str = "accessrequests.toml";
+
+/// Start parameter of the "Request access" button shown to untrusted users in inline mode
+pub const START_PARAMETER: &str = "request_access";
+/// Callback data of the buttons on access requests starts with this, then the decision and the user's id
+pub const CALLBACK_PREFIX: &str = "access:";
+/// How long "Approve for 7 days" lets a user in, in seconds
+pub const TEMPORARY_ACCESS: u64 = 60 * 60 * 24 * 7;
+/// How long a user has to wait before asking again with /request, in seconds
+pub const REQUEST_COOLDOWN: u64 = 60 * 60 * 24;
+
+/// Held while accessrequests.toml is read and written back, so that concurrent requests and decisions aren't lost
+static ACCESS_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RequestStatus {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AccessRequest {
+    pub status: RequestStatus,
+    /// when the user last asked, as a Unix timestamp
+    pub requested_at: u64,
+}
+
+/// Access requests by user id, kept so that users are only asked automatically once
+#[derive(Serialize, Deserialize, Default)]
+pub struct AccessRequests {
+    #[serde(default)]
+    pub requests: HashMap<String, AccessRequest>,
+}
+
+pub fn get_access_requests() -> Result<AccessRequests, Box<dyn std::error::Error + Send + Sync>> {
+    match std::fs::read_to_string(ACCESS_REQUESTS_PATH) {
+        Ok(content) => Ok(toml::from_str::<AccessRequests>(content.as_str())?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AccessRequests::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+fn set_access_requests(requests: AccessRequests) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let temp = format!("{ACCESS_REQUESTS_PATH}.tmp");
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(toml::to_string(&requests)?.as_bytes())?;
+    std::fs::rename(temp, ACCESS_REQUESTS_PATH)?;
+    Ok(())
+}
+
+/// Applies `update` to the requests in accessrequests.toml
+fn update_access_requests(update: impl FnOnce(&mut HashMap<String, AccessRequest>)) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _guard = ACCESS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut requests = get_access_requests()?;
+    update(&mut requests.requests);
+    set_access_requests(requests)
+}
+
+/// Whether `request` was made too recently for the user to ask again
+pub fn is_recent(request: &AccessRequest, now: u64) -> bool {
+    now < request.requested_at.saturating_add(REQUEST_COOLDOWN)
+}
+
+pub fn get_access_request(uid: String) -> Result<Option<AccessRequest>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(get_access_requests()?.requests.remove(&uid))
+}
+
+/// Records a new request from `uid`, waiting for a decision
+pub fn add_access_request(uid: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    update_access_requests(|requests| {
+        requests.insert(uid, AccessRequest { status: RequestStatus::Pending, requested_at: deepseek::page::now() });
+    })
+}
+
+pub fn set_access_request_status(uid: String, status: RequestStatus) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    update_access_requests(|requests| {
+        requests.entry(uid)
+            .or_insert(AccessRequest { status, requested_at: deepseek::page::now() })
+            .status = status;
+    })
+}
+
+/// A user as shown to superusers: their name, username and id
+pub fn describe_user(user: &User) -> String {
+    let mut ret = user.full_name();
+    if let Some(username) = &user.username {
+        ret.push_str(&format!(" (@{})", username));
+    }
+    ret.push_str(&format!(", id {}", user.id.0));
+    ret
+}
+
+/// The message asking superusers to let `user` in, quoting what they wrote, with the buttons to decide
+pub fn request_card(user: &User, message: &str) -> (String, InlineKeyboardMarkup) {
+    let mut text = format!("{} asks to use the bot.", describe_user(user));
+    if !message.trim().is_empty() {
+        text.push_str(&format!("\n\nMessage: {}", message.trim()));
+    }
+    let button = |label: &str, decision: &str| InlineKeyboardButton::callback(label, format!("{CALLBACK_PREFIX}{decision}:{}", user.id.0));
+    let keyboard = InlineKeyboardMarkup::new(vec![
+        vec![button("Approve", "approve"), button("Deny", "deny")],
+        vec![button("Approve for 7 days", "temporary")],
+    ]);
+    (text, keyboard)
+}
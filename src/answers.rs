@@ -0,0 +1,41 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// How many answers are kept before the oldest ones are forgotten
+const MAX_ANSWERS: usize = 1000;
+
+/// Recent answers by id: full answers that didn't fit in an inline message, kept so they can be read in a
+/// private chat, or what's needed to regenerate or continue an answer
+pub struct AnswerStore<T = String> {
+    answers: Mutex<(HashMap<String, T>, VecDeque<String>)>,
+}
+
+impl<T> Default for AnswerStore<T> {
+    fn default() -> Self {
+        Self { answers: Mutex::new((HashMap::new(), VecDeque::new())) }
+    }
+}
+
+impl<T: Clone> AnswerStore<T> {
+    /// Stores `answer` and returns its id, usable as a `/start` parameter or in callback data
+    pub fn insert(&self, key: &str, answer: T) -> String {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        std::time::SystemTime::now().hash(&mut hasher);
+        let id = format!("{:016x}", hasher.finish());
+        let mut guard = self.answers.lock().unwrap();
+        let (answers, order) = &mut *guard;
+        answers.insert(id.to_owned(), answer);
+        order.push_back(id.to_owned());
+        while order.len() > MAX_ANSWERS {
+            if let Some(oldest) = order.pop_front() {
+                answers.remove(&oldest);
+            }
+        }
+        id
+    }
+    pub fn get(&self, id: &str) -> Option<T> {
+        self.answers.lock().unwrap().0.get(id).cloned()
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config;
+use crate::keys;
+use deepseek::api::DeepSeekAPI;
+use deepseek::types::DeepSeekUserBalance;
+use serde::Deserialize;
+use std::collections::HashMap;
+use teloxide::prelude::*;
+
+/// Watching the balance of each DeepSeek API key, e.g. `[balance]` in the bot config. `interval` is in seconds, 0 to not
+/// watch it. With `restrict`, deepseek-reasoner is disabled while the balance is low.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct BalanceConfig {
+    pub interval: u64,
+    pub threshold: f64,
+    pub currency: String,
+    pub restrict: bool,
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: 600,
+            threshold: 1.0,
+            currency: String::from("USD"),
+            restrict: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Sufficient,
+    Low,
+    Unavailable,
+}
+
+fn status(balance: &DeepSeekUserBalance, config: &BalanceConfig) -> Status {
+    if !balance.is_available {
+        Status::Unavailable
+    } else if balance.total(&config.currency).is_some_and(|total| total < config.threshold) {
+        Status::Low
+    } else {
+        Status::Sufficient
+    }
+}
+
+async fn alert(bot: &Bot, text: &str) {
+    let superusers = match config::get_config() {
+        Ok(config) => config.superusers(),
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            return;
+        }
+    };
+    for superuser in superusers {
+        match superuser.parse::<i64>() {
+            Ok(chat_id) => {
+                if let Err(e) = bot.send_message(ChatId(chat_id), text).await {
+                    log::error!("Error sending balance alert to {}: {}", superuser, e);
+                }
+            }
+            Err(e) => log::error!("Invalid superuser uid {}: {}", superuser, e),
+        }
+    }
+}
+
+/// Checks the balance of every key in use every `interval`, alerting superusers when one runs low or its account
+/// becomes unavailable, and again once it recovers
+pub async fn watch(bot: Bot, api: DeepSeekAPI) {
+    let mut last = HashMap::<String, Status>::new();
+    loop {
+        let config = match config::get_config() {
+            Ok(config) => config.balance,
+            Err(e) => {
+                log::error!("Error reading config: {}", e);
+                BalanceConfig::default()
+            }
+        };
+        if config.interval == 0 {
+            for key in keys::usable() {
+                keys::set_restricted(&key.name, false);
+            }
+            return;
+        }
+        for key in keys::usable() {
+            let api = DeepSeekAPI { token: key.token, ..api.clone() };
+            match api.get_balance().await {
+                Ok(balance) => {
+                    let status = status(&balance, &config);
+                    let restricted = config.restrict && status != Status::Sufficient;
+                    keys::set_restricted(&key.name, restricted);
+                    let last = last.entry(key.name.to_owned()).or_insert(Status::Sufficient);
+                    if status != *last {
+                        let total = balance.total(&config.currency).map(|total| format!("{} {}", total, config.currency)).unwrap_or_else(|| String::from("unknown"));
+                        let mut text = match status {
+                            Status::Sufficient => format!("The DeepSeek balance of key {} has recovered: {}.", key.name, total),
+                            Status::Low => format!("The DeepSeek balance of key {} is low: {}, below {} {}.", key.name, total, config.threshold, config.currency),
+                            Status::Unavailable => format!("The DeepSeek account of key {} is no longer available. Balance: {}.", key.name, total),
+                        };
+                        if restricted {
+                            text.push_str(" deepseek-reasoner is disabled with this key until the balance is topped up.");
+                        } else if *last != Status::Sufficient && config.restrict {
+                            text.push_str(" deepseek-reasoner is enabled again.");
+                        }
+                        log::warn!("{}", text);
+                        alert(&bot, &text).await;
+                        *last = status;
+                    }
+                }
+                Err(e) => log::error!("Error when fetching balance information of key {}: {}", key.name, e),
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(config.interval)).await;
+    }
+}
@@ -1,17 +1,99 @@
 use serde::Serialize;
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io::prelude::*;
+use std::sync::Mutex;
+
+/// Held while trustedusers.toml is read and written back, so that changes aren't lost
+static TRUSTED_LOCK: Mutex<()> = Mutex::new(());
+
+fn default_attachment_threshold() -> usize {
+    8000
+}
+
+fn default_max_continuations() -> usize {
+    2
+}
+
+/// Quick answers shown in the inline results as the user types, `[inline_preview]` in the config.
+/// `debounce` is in milliseconds, `cache_time` in seconds. Queries shorter than `min_query_chars` get no preview,
+/// and each user gets at most `hourly_limit` of them, 0 for no limit.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct InlinePreviewConfig {
+    pub enabled: bool,
+    pub debounce: u64,
+    pub cache_time: u32,
+    pub min_query_chars: usize,
+    pub hourly_limit: usize,
+}
+
+impl Default for InlinePreviewConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            debounce: 800,
+            cache_time: 300,
+            min_query_chars: 10,
+            hourly_limit: 30,
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct Config {
     pub telegram_bot_token: String,
     pub deepseek_api_token: String,
+    /// further keys to share the requests with, or to use for particular users and chats
+    #[serde(default)]
+    pub api_keys: Vec<crate::keys::ApiKeyConfig>,
+    #[serde(default)]
+    pub key_selection: crate::keys::KeySelection,
     pub superuser_uid: String,
+    /// further users who can /grant others and decide on access requests
+    #[serde(default)]
+    pub superuser_uids: Vec<String>,
+    /// messages in groups starting with one of these are answered, as if the bot was mentioned
+    #[serde(default)]
+    pub group_trigger_prefixes: Vec<String>,
+    /// answers longer than this many characters are sent as files, 0 to never do so
+    #[serde(default = "default_attachment_threshold")]
+    pub attachment_threshold: usize,
+    /// answers cut off by the token limit are continued automatically this many times at most
+    #[serde(default = "default_max_continuations")]
+    pub max_continuations: usize,
+    /// system prompts by name, which chats can switch to with /persona
+    #[serde(default)]
+    pub personas: BTreeMap<String, String>,
+    #[serde(default)]
+    pub search: deepseek::search::SearchConfig,
+    #[serde(default)]
+    pub summary: deepseek::search::SummaryConfig,
+    #[serde(default)]
+    pub cache: deepseek::cache::CacheConfig,
+    #[serde(default)]
+    pub inline_preview: InlinePreviewConfig,
+    #[serde(default)]
+    pub pricing: crate::usage::PricingConfig,
+    #[serde(default)]
+    pub balance: crate::balance::BalanceConfig,
+    #[serde(default)]
+    pub limiter: deepseek::limiter::LimiterConfig,
+}
+
+impl Config {
+    /// `superuser_uid` and `superuser_uids` together
+    pub fn superusers(&self) -> Vec<String> {
+        std::iter::once(self.superuser_uid.to_owned()).chain(self.superuser_uids.iter().cloned()).collect()
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct TrustedUsers {
     pub trusted_users: Vec<String>,
+    /// when the access of users let in for a while ends, as a Unix timestamp
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub expires: BTreeMap<String, u64>,
 }
 
 pub fn get_config() -> Result<Config, Box<dyn std::error::Error + Send + Sync>> {
@@ -19,31 +101,136 @@ pub fn get_config() -> Result<Config, Box<dyn std::error::Error + Send + Sync>>
     Ok(config)
 }
 
+/// The trusted users, leaving out those whose access has expired
 pub fn get_trusted_users() -> Result<TrustedUsers, Box<dyn std::error::Error + Send + Sync>> {
-    let trusted_users = toml::from_str::<TrustedUsers>(std::fs::read_to_string("trustedusers.toml")?.as_str())?;
+    let mut trusted_users = toml::from_str::<TrustedUsers>(std::fs::read_to_string("trustedusers.toml")?.as_str())?;
+    trusted_users.drop_expired(deepseek::page::now());
     Ok(trusted_users)
 }
 
+impl TrustedUsers {
+    /// Removes the users whose access ended before `now`, returning whether there were any
+    fn drop_expired(&mut self, now: u64) -> bool {
+        let expired = self.expires.iter()
+            .filter(|(_, &expires)| expires <= now)
+            .map(|(uid, _)| uid.to_owned())
+            .collect::<Vec<_>>();
+        for uid in &expired {
+            log::info!("Access of user {} expired", uid);
+            self.expires.remove(uid);
+            self.trusted_users.retain(|x| x != uid);
+        }
+        !expired.is_empty()
+    }
+    /// Trusts `uid` until the Unix timestamp `expires`, or for good if it is `None`, never shortening the access
+    /// the user already has. Returns when the user's access ends now.
+    fn trust(&mut self, uid: String, expires: Option<u64>) -> Option<u64> {
+        let trusted = self.trusted_users.contains(&uid);
+        let expires = match (expires, self.expires.get(&uid)) {
+            (None, _) => None,
+            (Some(_), None) if trusted => None,
+            (Some(expires), Some(&current)) => Some(expires.max(current)),
+            (Some(expires), None) => Some(expires),
+        };
+        match expires {
+            Some(expires) => self.expires.insert(uid.to_owned(), expires),
+            None => self.expires.remove(&uid),
+        };
+        if !trusted {
+            self.trusted_users.push(uid);
+        }
+        expires
+    }
+}
+
+/// Applies `update` to trustedusers.toml, dropping the users whose access has expired on the way
+fn update_trusted_users<T>(update: impl FnOnce(&mut TrustedUsers) -> T) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    let _guard = TRUSTED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut trusted_users = get_trusted_users()?;
+    let ret = update(&mut trusted_users);
+    set_trusted_users(trusted_users)?;
+    Ok(ret)
+}
+
+/// Removes the users whose access has expired from trustedusers.toml
+pub fn prune_trusted_users() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _guard = TRUSTED_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut trusted_users = toml::from_str::<TrustedUsers>(std::fs::read_to_string("trustedusers.toml")?.as_str())?;
+    if trusted_users.drop_expired(deepseek::page::now()) {
+        set_trusted_users(trusted_users)?;
+    }
+    Ok(())
+}
+
 pub fn set_trusted_users(trusted_users: TrustedUsers) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut file = std::fs::File::create("trustedusers.toml")?;
+    let mut file = std::fs::File::create("trustedusers.toml.tmp")?;
     file.write_all(toml::to_string(&trusted_users)?.as_bytes())?;
+    std::fs::rename("trustedusers.toml.tmp", "trustedusers.toml")?;
     Ok(())
 }
 
 pub fn add_trusted_user(uid: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut trusted_users = get_trusted_users()?;
-    if trusted_users.trusted_users.iter().find(|x| **x == uid) == None {
-        trusted_users.trusted_users.push(uid);
-    }
-    set_trusted_users(trusted_users)?;
+    add_trusted_user_until(uid, None)?;
     Ok(())
 }
 
+/// Trusts `uid` until the Unix timestamp `expires`, or for good if it is `None`. Access is only ever extended, so
+/// a user trusted for good stays so. Returns when the user's access ends now.
+pub fn add_trusted_user_until(uid: String, expires: Option<u64>) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+    update_trusted_users(|trusted_users| trusted_users.trust(uid, expires))
+}
+
+#[allow(dead_code)]
 pub fn del_trusted_user(uid: String) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut trusted_users = get_trusted_users()?;
-    if let Some(idx) = trusted_users.trusted_users.iter().position(|x| **x == uid) {
-        trusted_users.trusted_users.remove(idx);
+    update_trusted_users(|trusted_users| {
+        trusted_users.trusted_users.retain(|x| *x != uid);
+        trusted_users.expires.remove(&uid);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted(users: &[&str], expires: &[(&str, u64)]) -> TrustedUsers {
+        TrustedUsers {
+            trusted_users: users.iter().map(|uid| uid.to_string()).collect(),
+            expires: expires.iter().map(|(uid, expires)| (uid.to_string(), *expires)).collect(),
+        }
+    }
+
+    #[test]
+    fn drops_only_expired_users() {
+        let mut users = trusted(&["1", "2", "3"], &[("2", 100), ("3", 200)]);
+        assert!(users.drop_expired(100));
+        assert_eq!(users.trusted_users, ["1", "3"]);
+        assert_eq!(users.expires.keys().collect::<Vec<_>>(), ["3"]);
+        assert!(!users.drop_expired(150));
+        assert_eq!(users.trusted_users, ["1", "3"]);
+    }
+
+    #[test]
+    fn trusts_new_users_for_a_while_or_for_good() {
+        let mut users = trusted(&[], &[]);
+        assert_eq!(users.trust(String::from("1"), Some(100)), Some(100));
+        assert_eq!(users.trust(String::from("2"), None), None);
+        assert_eq!(users.trusted_users, ["1", "2"]);
+        assert_eq!(users.expires.get("1"), Some(&100));
+        assert!(!users.expires.contains_key("2"));
+    }
+
+    #[test]
+    fn never_shortens_access() {
+        let mut users = trusted(&["1", "2"], &[("2", 500)]);
+        // trusted for good stays so
+        assert_eq!(users.trust(String::from("1"), Some(100)), None);
+        assert!(!users.expires.contains_key("1"));
+        // a later end is kept, an earlier one extended
+        assert_eq!(users.trust(String::from("2"), Some(300)), Some(500));
+        assert_eq!(users.trust(String::from("2"), Some(700)), Some(700));
+        // approving for good lifts the end
+        assert_eq!(users.trust(String::from("2"), None), None);
+        assert!(users.expires.is_empty());
+        assert_eq!(users.trusted_users, ["1", "2"]);
     }
-    set_trusted_users(trusted_users)?;
-    Ok(())
 }
@@ -0,0 +1,65 @@
+use std::io::Read;
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::Document;
+
+/// Bots can't download files larger than this
+pub const MAX_DOCUMENT_SIZE: u32 = 20 * 1024 * 1024;
+/// How much of the indexed documents is given to the model for one question, well within its context
+pub const CONTEXT_CHARS: usize = 32000;
+
+pub const PASSAGES_PROMPT: &str = "The user has shared documents. The passages of them most relevant to the user's question are given below, each between <passage> tags that name the document it comes from.\n{content}\nAnswer the user's question based on these passages, mentioning which document the information comes from when there are several. If the passages don't contain the answer, say so. PLEASE ANSWER THE QUERY IN THE SAME LANGUAGE THAT IT'S ASKED!";
+/// Asked when a document is sent without a caption
+pub const DEFAULT_QUESTION: &str = "Summarize this document.";
+
+const DOCX_MIME: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+
+fn extension(file_name: &str) -> String {
+    file_name.rsplit_once('.').map(|(_, extension)| extension.to_lowercase()).unwrap_or_default()
+}
+
+pub async fn download(bot: &Bot, document: &Document) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let file = bot.get_file(document.file.id.to_owned()).await?;
+    let mut content = Vec::with_capacity(file.size as usize);
+    bot.download_file(&file.path, &mut content).await?;
+    Ok(content)
+}
+
+/// Reads the text of a plain text, Markdown, source code, HTML, PDF or DOCX file
+pub async fn extract_text(document: &Document, content: Vec<u8>) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let extension = extension(document.file_name.as_deref().unwrap_or_default());
+    let mime = document.mime_type.as_ref().map(|mime| mime.essence_str().to_string()).unwrap_or_default();
+    let text = if extension == "pdf" || mime == "application/pdf" {
+        // parsing is CPU-bound and panics on some malformed files
+        tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&content)).await??
+    } else if extension == "docx" || mime == DOCX_MIME {
+        docx_text(&content)?
+    } else if ["html", "htm", "xhtml"].contains(&extension.as_str()) || mime == "text/html" {
+        deepseek::page::extract_text(&String::from_utf8_lossy(&content))
+    } else {
+        match String::from_utf8(content) {
+            Ok(text) => text,
+            Err(_) => return Err(format!("unsupported file type: {}", if mime.is_empty() { extension } else { mime }).into()),
+        }
+    };
+    Ok(text.trim_start_matches('\u{feff}').trim().to_string())
+}
+
+/// The paragraphs of a Word document, one per line
+fn docx_text(content: &[u8]) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(content))?;
+    let mut xml = String::new();
+    archive.by_name("word/document.xml")?.read_to_string(&mut xml)?;
+    let breaks = regex::Regex::new(r"</w:p>|<w:br\s*/>|<w:cr\s*/>").unwrap();
+    let tabs = regex::Regex::new(r"<w:tab\s*/>").unwrap();
+    let tags = regex::Regex::new(r"<[^>]*>").unwrap();
+    let xml = breaks.replace_all(&xml, "\n");
+    let xml = tabs.replace_all(&xml, "\t");
+    let text = tags.replace_all(&xml, "");
+    Ok(text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&"))
+}
@@ -0,0 +1,313 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+const DOCUMENT_INDEX_PATH: &str = "documentindex.json";
+/// Size of the passages documents are cut into
+const PASSAGE_CHARS: usize = 2000;
+/// How much consecutive passages share, so facts spanning a cut are still found together
+const PASSAGE_OVERLAP: usize = 400;
+/// Older documents are forgotten beyond this many per chat
+const MAX_DOCUMENTS_PER_CHAT: usize = 20;
+/// BM25 term frequency saturation
+const K1: f64 = 1.2;
+/// BM25 length normalization
+const B: f64 = 0.75;
+
+#[derive(Serialize, Deserialize)]
+pub struct IndexedDocument {
+    /// Telegram's unique id of the file
+    pub id: String,
+    pub name: String,
+    pub chars: usize,
+    pub passages: Vec<String>,
+}
+
+/// A passage picked for a question
+pub struct Passage {
+    pub document: String,
+    pub number: usize,
+    pub text: String,
+}
+
+/// The passages picked for a question, and whether they are everything that was indexed
+pub struct Retrieval {
+    pub passages: Vec<Passage>,
+    pub complete: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct IndexData {
+    #[serde(default)]
+    chats: HashMap<String, Vec<IndexedDocument>>,
+}
+
+/// Documents sent to each chat, cut into passages and ranked with BM25 for each question
+pub struct DocumentIndex {
+    data: Mutex<IndexData>,
+    /// held while writing the file
+    saving: Mutex<()>,
+}
+
+/// Lowercased words, with CJK characters taken one by one since they aren't separated by spaces
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                tokens.push(std::mem::take(&mut word));
+            }
+            tokens.push(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        } else if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32, 0x3040..=0x30FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xF900..=0xFAFF | 0x20000..=0x2FA1F)
+}
+
+/// Cuts `text` into passages of about `size` characters overlapping by `overlap`, preferring to cut at whitespace
+fn split_passages(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let chars = text.char_indices().map(|(index, _)| index).chain(std::iter::once(text.len())).collect::<Vec<_>>();
+    let count = chars.len() - 1;
+    let mut passages = Vec::new();
+    let mut start = 0;
+    while start < count {
+        let mut end = (start + size).min(count);
+        if end < count {
+            // back off to the last whitespace in the second half of the passage
+            if let Some(space) = (start + size / 2..end).rev().find(|&i| text[chars[i]..].starts_with(char::is_whitespace)) {
+                end = space;
+            }
+        }
+        let passage = text[chars[start]..chars[end]].trim();
+        if !passage.is_empty() {
+            passages.push(passage.to_string());
+        }
+        if end >= count {
+            break;
+        }
+        // start the next passage at a word boundary within the overlap
+        let overlap_start = end.saturating_sub(overlap).max(start + 1);
+        start = (overlap_start..end)
+            .find(|&i| text[chars[i - 1]..].starts_with(char::is_whitespace))
+            .unwrap_or(overlap_start);
+    }
+    passages
+}
+
+/// BM25 scores of `passages` for `query`
+fn bm25(query: &str, passages: &[&str]) -> Vec<f64> {
+    let terms = tokenize(query).into_iter().collect::<HashSet<_>>();
+    let passages = passages.iter().map(|passage| tokenize(passage)).collect::<Vec<_>>();
+    let count = passages.len() as f64;
+    let average_length = passages.iter().map(|tokens| tokens.len()).sum::<usize>() as f64 / count.max(1.0);
+    let frequencies = passages.iter()
+        .map(|tokens| {
+            let mut frequency = HashMap::<&str, f64>::new();
+            for token in tokens.iter().filter(|token| terms.contains(*token)) {
+                *frequency.entry(token.as_str()).or_default() += 1.0;
+            }
+            frequency
+        })
+        .collect::<Vec<_>>();
+    let idf = terms.iter()
+        .map(|term| {
+            let containing = frequencies.iter().filter(|frequency| frequency.contains_key(term.as_str())).count() as f64;
+            (term.as_str(), ((count - containing + 0.5) / (containing + 0.5) + 1.0).ln())
+        })
+        .collect::<HashMap<_, _>>();
+    frequencies.iter()
+        .zip(passages.iter())
+        .map(|(frequency, tokens)| {
+            let length = tokens.len() as f64 / average_length.max(1.0);
+            frequency.iter()
+                .map(|(term, tf)| idf[term] * tf * (K1 + 1.0) / (tf + K1 * (1.0 - B + B * length)))
+                .sum()
+        })
+        .collect()
+}
+
+impl DocumentIndex {
+    /// Reads the index persisted on disk, starting empty if it is missing or unreadable.
+    pub fn load() -> Self {
+        let data = std::fs::read_to_string(DOCUMENT_INDEX_PATH)
+            .ok()
+            .and_then(|content| serde_json::from_str::<IndexData>(&content).ok())
+            .unwrap_or_default();
+        Self { data: Mutex::new(data), saving: Mutex::new(()) }
+    }
+    /// Writes the index to disk off the async runtime
+    pub async fn save(self: &Arc<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let index = self.clone();
+        tokio::task::spawn_blocking(move || index.write()).await?
+    }
+    /// Writes through a temporary file, so that the index on disk is never left half-written
+    fn write(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let _saving = self.saving.lock().unwrap();
+        let content = serde_json::to_string(&*self.data.lock().unwrap())?;
+        let temp = format!("{DOCUMENT_INDEX_PATH}.tmp");
+        std::fs::write(&temp, content)?;
+        std::fs::rename(temp, DOCUMENT_INDEX_PATH)?;
+        Ok(())
+    }
+    pub fn contains(&self, chat_id: &str, id: &str) -> bool {
+        self.data.lock().unwrap().chats.get(chat_id).is_some_and(|documents| documents.iter().any(|document| document.id == id))
+    }
+    /// Indexes `text` for the chat, replacing an earlier copy of the same file
+    pub fn insert(&self, chat_id: &str, id: &str, name: &str, text: &str) {
+        let document = IndexedDocument {
+            id: id.to_string(),
+            name: name.to_string(),
+            chars: text.chars().count(),
+            passages: split_passages(text, PASSAGE_CHARS, PASSAGE_OVERLAP),
+        };
+        let mut data = self.data.lock().unwrap();
+        let documents = data.chats.entry(chat_id.to_string()).or_default();
+        documents.retain(|indexed| indexed.id != id);
+        documents.push(document);
+        if documents.len() > MAX_DOCUMENTS_PER_CHAT {
+            let excess = documents.len() - MAX_DOCUMENTS_PER_CHAT;
+            documents.drain(..excess);
+        }
+    }
+    /// Names and sizes of the chat's documents, oldest first
+    pub fn documents(&self, chat_id: &str) -> Vec<(String, usize)> {
+        self.data.lock().unwrap().chats.get(chat_id)
+            .map(|documents| documents.iter().map(|document| (document.name.to_owned(), document.chars)).collect())
+            .unwrap_or_default()
+    }
+    /// Forgets the `number`th document as listed by `documents`, returning its name
+    pub fn remove(&self, chat_id: &str, number: usize) -> Option<String> {
+        let mut data = self.data.lock().unwrap();
+        let documents = data.chats.get_mut(chat_id)?;
+        if number == 0 || number > documents.len() {
+            return None;
+        }
+        let removed = documents.remove(number - 1);
+        if documents.is_empty() {
+            data.chats.remove(chat_id);
+        }
+        Some(removed.name)
+    }
+    /// Forgets all of the chat's documents, returning how many there were
+    pub fn clear(&self, chat_id: &str) -> usize {
+        self.data.lock().unwrap().chats.remove(chat_id).map(|documents| documents.len()).unwrap_or_default()
+    }
+    /// Picks the passages most relevant to `query` that fit in `max_chars`, from the document `id` or from all
+    /// of the chat's documents. They are returned in reading order.
+    pub fn retrieve(&self, chat_id: &str, id: Option<&str>, query: &str, max_chars: usize) -> Retrieval {
+        let data = self.data.lock().unwrap();
+        let candidates = data.chats.get(chat_id)
+            .into_iter()
+            .flatten()
+            .filter(|document| id.is_none_or(|id| document.id == id))
+            .flat_map(|document| document.passages.iter().enumerate().map(move |(i, passage)| (document.name.as_str(), i, passage.as_str())))
+            .collect::<Vec<_>>();
+        let total = candidates.iter().map(|(_, _, passage)| passage.chars().count()).sum::<usize>();
+        let mut picked = (0..candidates.len()).collect::<Vec<_>>();
+        let complete = total <= max_chars;
+        if !complete {
+            let scores = bm25(query, &candidates.iter().map(|(_, _, passage)| *passage).collect::<Vec<_>>());
+            // stable, so passages nothing matched keep their order and the beginning is preferred
+            picked.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+            let mut budget = max_chars;
+            picked.retain(|&i| {
+                let length = candidates[i].2.chars().count();
+                let fits = length <= budget;
+                if fits {
+                    budget -= length;
+                }
+                fits
+            });
+            picked.sort();
+        }
+        Retrieval {
+            passages: picked.into_iter()
+                .map(|i| {
+                    let (document, number, text) = candidates[i];
+                    Passage { document: document.to_string(), number: number + 1, text: text.to_string() }
+                })
+                .collect(),
+            complete,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_words_and_cjk_characters() {
+        assert_eq!(tokenize("Hello, World! 你好abc"), vec!["hello", "world", "你", "好", "abc"]);
+    }
+
+    #[test]
+    fn keeps_short_text_in_one_passage() {
+        assert_eq!(split_passages("  short text  ", 100, 20), vec!["short text"]);
+        assert!(split_passages("", 100, 20).is_empty());
+    }
+
+    #[test]
+    fn splits_at_whitespace_with_overlap() {
+        let text = (0..100).map(|i| format!("word{i}")).collect::<Vec<_>>().join(" ");
+        let passages = split_passages(&text, 100, 30);
+        assert!(passages.len() > 1);
+        for passage in &passages {
+            assert!(passage.chars().count() <= 100);
+            // no word is cut in half
+            assert!(passage.split(' ').all(|word| text.split(' ').any(|original| original == word)));
+        }
+        for pair in passages.windows(2) {
+            let last = pair[0].split(' ').next_back().unwrap();
+            assert!(pair[1].split(' ').any(|word| word == last), "consecutive passages overlap");
+        }
+        assert!(passages.first().unwrap().starts_with("word0 "));
+        assert!(passages.last().unwrap().ends_with(" word99"));
+    }
+
+    #[test]
+    fn splits_text_without_whitespace_by_characters() {
+        let text = "字".repeat(250);
+        let passages = split_passages(&text, 100, 20);
+        assert!(passages.iter().all(|passage| passage.chars().count() <= 100));
+        assert_eq!(passages.concat().chars().count(), 250 + 20 * (passages.len() - 1));
+    }
+
+    #[test]
+    fn ranks_passages_by_bm25() {
+        let passages = ["the cat sat on the mat", "dogs chase cats", "the cat chased the cat", "nothing relevant here"];
+        let scores = bm25("cat", &passages);
+        assert_eq!(scores.len(), 4);
+        assert!(scores[2] > scores[0]);
+        assert!(scores[0] > 0.0);
+        assert_eq!(scores[1], 0.0);
+        assert_eq!(scores[3], 0.0);
+        // rarer terms weigh more
+        let scores = bm25("cat dogs", &passages);
+        assert!(scores[1] > scores[0]);
+    }
+
+    #[test]
+    fn retrieves_relevant_passages_in_reading_order() {
+        let index = DocumentIndex { data: Mutex::new(IndexData::default()), saving: Mutex::new(()) };
+        let text = ["alpha ".repeat(400), "beta ".repeat(400), "gamma ".repeat(400)].concat();
+        index.insert("chat", "file", "doc.txt", &text);
+        let retrieval = index.retrieve("chat", None, "gamma beta", 4500);
+        assert!(!retrieval.complete);
+        assert!(retrieval.passages.iter().all(|passage| !passage.text.starts_with("alpha alpha")));
+        assert!(retrieval.passages.windows(2).all(|pair| pair[0].number < pair[1].number));
+        assert!(index.retrieve("chat", None, "anything", usize::MAX).complete);
+        assert!(index.retrieve("other", None, "gamma", 4500).passages.is_empty());
+    }
+}
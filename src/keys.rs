@@ -0,0 +1,296 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Name of the key given as `deepseek_api_token`
+pub const DEFAULT_KEY: &str = "default";
+
+/// A further DeepSeek API key, `[[api_keys]]` in the bot config. A key assigned to users or chats is only used
+/// for them, e.g. for a team paying for its own usage.
+#[derive(Deserialize, Clone)]
+pub struct ApiKeyConfig {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub chats: Vec<String>,
+}
+
+/// How a key is picked among those not assigned to anyone
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum KeySelection {
+    #[default]
+    RoundRobin,
+    LeastSpent,
+}
+
+struct Key {
+    config: ApiKeyConfig,
+    /// set once the key is refused, for being wrong or out of balance
+    removed: AtomicBool,
+    /// set while the key's balance is low and deepseek-reasoner is not to be used with it
+    restricted: AtomicBool,
+    /// start of the month as a Unix timestamp, and the estimated spend since then in USD
+    spent: Mutex<(u64, f64)>,
+}
+
+impl Key {
+    fn assigned(&self) -> bool {
+        !self.config.users.is_empty() || !self.config.chats.is_empty()
+    }
+    fn usable(&self) -> bool {
+        !self.removed.load(Ordering::Relaxed)
+    }
+    fn spent(&self) -> f64 {
+        *this_month(&mut self.spent.lock().unwrap())
+    }
+}
+
+/// The spend in `spent`, starting over once a new month begins
+fn this_month(spent: &mut (u64, f64)) -> &mut f64 {
+    let month = crate::usage::month_start(deepseek::page::now());
+    if spent.0 != month {
+        *spent = (month, 0.0);
+    }
+    &mut spent.1
+}
+
+struct KeyPool {
+    keys: Vec<Key>,
+    selection: KeySelection,
+    next: AtomicUsize,
+}
+
+static POOL: OnceLock<KeyPool> = OnceLock::new();
+
+/// A key picked for a request
+pub struct SelectedKey {
+    pub name: String,
+    pub token: String,
+}
+
+impl KeyPool {
+    fn new(default_token: String, keys: Vec<ApiKeyConfig>, selection: KeySelection, spent: HashMap<String, f64>) -> Self {
+        let default = ApiKeyConfig { name: String::from(DEFAULT_KEY), token: default_token, users: Vec::new(), chats: Vec::new() };
+        let month = crate::usage::month_start(deepseek::page::now());
+        let keys = std::iter::once(default)
+            .chain(keys)
+            .map(|config| Key {
+                spent: Mutex::new((month, spent.get(&config.name).copied().unwrap_or_default())),
+                config,
+                removed: AtomicBool::new(false),
+                restricted: AtomicBool::new(false),
+            })
+            .collect();
+        Self { keys, selection, next: AtomicUsize::new(0) }
+    }
+    fn key(&self, name: &str) -> Option<&Key> {
+        self.keys.iter().find(|key| key.config.name == name)
+    }
+    /// The usable keys that may be picked for `user` in `chat`, and whether they are assigned to them. Users and
+    /// chats with keys of their own never fall back to the shared ones, even once their keys are removed.
+    fn candidates(&self, user: u64, chat: Option<i64>) -> (Vec<&Key>, bool) {
+        let (user, chat) = (user.to_string(), chat.map(|chat| chat.to_string()));
+        let assigned = self.keys.iter()
+            .filter(|key| key.config.users.contains(&user) || chat.as_ref().is_some_and(|chat| key.config.chats.contains(chat)))
+            .collect::<Vec<_>>();
+        if !assigned.is_empty() {
+            (assigned.into_iter().filter(|key| key.usable()).collect(), true)
+        } else {
+            (self.keys.iter().filter(|key| key.usable() && !key.assigned()).collect(), false)
+        }
+    }
+    fn select(&self, user: u64, chat: Option<i64>) -> Option<SelectedKey> {
+        let key = match self.candidates(user, chat) {
+            (keys, true) => keys.into_iter().next()?,
+            (keys, false) => match self.selection {
+                KeySelection::RoundRobin if !keys.is_empty() => keys[self.next.fetch_add(1, Ordering::Relaxed) % keys.len()],
+                KeySelection::LeastSpent => keys.into_iter().min_by(|a, b| a.spent().total_cmp(&b.spent()))?,
+                _ => return None,
+            },
+        };
+        Some(SelectedKey { name: key.config.name.to_owned(), token: key.config.token.to_owned() })
+    }
+    fn remove(&self, name: &str) {
+        if let Some(key) = self.key(name) {
+            key.removed.store(true, Ordering::Relaxed);
+        }
+    }
+    fn add_spend(&self, name: &str, cost: f64) {
+        if let Some(key) = self.key(name) {
+            *this_month(&mut key.spent.lock().unwrap()) += cost;
+        }
+    }
+    fn restricted(&self, user: u64, chat: Option<i64>) -> bool {
+        let (keys, _) = self.candidates(user, chat);
+        !keys.is_empty() && keys.iter().all(|key| key.restricted.load(Ordering::Relaxed))
+    }
+}
+
+/// Sets up the pool with `deepseek_api_token` as the default key, followed by `keys`. `spent` is what each key
+/// has cost this month so far.
+pub fn init(default_token: String, keys: Vec<ApiKeyConfig>, selection: KeySelection, spent: HashMap<String, f64>) {
+    if POOL.set(KeyPool::new(default_token, keys, selection, spent)).is_err() {
+        log::warn!("API keys were already set up");
+    }
+}
+
+/// The key to use for `user` in `chat`: one assigned to them, or else one of the shared keys
+pub fn select(user: u64, chat: Option<i64>) -> Option<SelectedKey> {
+    POOL.get()?.select(user, chat)
+}
+
+/// The usable keys that may be picked for `user` in `chat`
+pub fn serving(user: u64, chat: Option<i64>) -> Vec<SelectedKey> {
+    let Some(pool) = POOL.get() else {
+        return Vec::new();
+    };
+    pool.candidates(user, chat).0.into_iter()
+        .map(|key| SelectedKey { name: key.config.name.to_owned(), token: key.config.token.to_owned() })
+        .collect()
+}
+
+/// Every key still in use
+pub fn usable() -> Vec<SelectedKey> {
+    let Some(pool) = POOL.get() else {
+        return Vec::new();
+    };
+    pool.keys.iter()
+        .filter(|key| key.usable())
+        .map(|key| SelectedKey { name: key.config.name.to_owned(), token: key.config.token.to_owned() })
+        .collect()
+}
+
+/// Marks the key `name` as low on balance, so that deepseek-reasoner isn't used with it
+pub fn set_restricted(name: &str, restricted: bool) {
+    if let Some(key) = POOL.get().and_then(|pool| pool.key(name)) {
+        key.restricted.store(restricted, Ordering::Relaxed);
+    }
+}
+
+/// Whether all the keys that may be picked for `user` in `chat` are low on balance
+pub fn restricted(user: u64, chat: Option<i64>) -> bool {
+    POOL.get().is_some_and(|pool| pool.restricted(user, chat))
+}
+
+/// Stops using the key `name`
+pub fn remove(name: &str) {
+    if let Some(pool) = POOL.get() {
+        pool.remove(name);
+    }
+}
+
+pub fn add_spend(name: &str, cost: f64) {
+    if let Some(pool) = POOL.get() {
+        pool.add_spend(name, cost);
+    }
+}
+
+/// Each key with its spend this month and whether it is still used, one per line
+pub fn describe() -> String {
+    let Some(pool) = POOL.get() else {
+        return String::new();
+    };
+    pool.keys.iter()
+        .map(|key| format!(
+            "{}: ${:.4}{}{}{}",
+            key.config.name,
+            key.spent(),
+            if key.assigned() { ", assigned" } else { "" },
+            if key.restricted.load(Ordering::Relaxed) { ", balance low" } else { "" },
+            if key.usable() { "" } else { ", removed" },
+        ))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str, users: &[&str], chats: &[&str]) -> ApiKeyConfig {
+        ApiKeyConfig {
+            name: name.to_string(),
+            token: format!("{name}-token"),
+            users: users.iter().map(|user| user.to_string()).collect(),
+            chats: chats.iter().map(|chat| chat.to_string()).collect(),
+        }
+    }
+
+    /// The default key, shared keys a and b, and key team for user 7 and chat -100
+    fn pool(selection: KeySelection, spent: &[(&str, f64)]) -> KeyPool {
+        let keys = vec![key("a", &[], &[]), key("b", &[], &[]), key("team", &["7"], &["-100"])];
+        let spent = spent.iter().map(|(name, spent)| (name.to_string(), *spent)).collect();
+        KeyPool::new(String::from("default-token"), keys, selection, spent)
+    }
+
+    fn picks(pool: &KeyPool, user: u64, chat: Option<i64>, times: usize) -> Vec<String> {
+        (0..times).map(|_| pool.select(user, chat).map(|key| key.name).unwrap_or_default()).collect()
+    }
+
+    #[test]
+    fn takes_turns_between_shared_keys() {
+        let pool = pool(KeySelection::RoundRobin, &[]);
+        assert_eq!(picks(&pool, 1, Some(1), 4), ["default", "a", "b", "default"]);
+        assert_eq!(pool.select(1, None).unwrap().token, "a-token");
+    }
+
+    #[test]
+    fn picks_the_least_spent_shared_key() {
+        let pool = pool(KeySelection::LeastSpent, &[("default", 3.0), ("a", 1.0), ("b", 2.0), ("team", 0.0)]);
+        assert_eq!(picks(&pool, 1, None, 2), ["a", "a"]);
+        pool.add_spend("a", 1.5);
+        assert_eq!(picks(&pool, 1, None, 1), ["b"]);
+    }
+
+    #[test]
+    fn uses_keys_assigned_to_users_and_chats() {
+        let pool = pool(KeySelection::RoundRobin, &[]);
+        assert_eq!(picks(&pool, 7, None, 2), ["team", "team"]);
+        assert_eq!(picks(&pool, 1, Some(-100), 1), ["team"]);
+        // assigned keys aren't shared with anyone else
+        assert!(!picks(&pool, 1, Some(1), 6).contains(&String::from("team")));
+    }
+
+    #[test]
+    fn stops_using_removed_keys() {
+        let pool = pool(KeySelection::RoundRobin, &[]);
+        pool.remove("default");
+        pool.remove("b");
+        assert_eq!(picks(&pool, 1, None, 3), ["a", "a", "a"]);
+        pool.remove("a");
+        assert!(pool.select(1, None).is_none());
+        // users with keys of their own don't fall back to the shared ones
+        let pool = self::pool(KeySelection::RoundRobin, &[]);
+        pool.remove("team");
+        assert!(pool.select(7, None).is_none());
+    }
+
+    #[test]
+    fn restricts_only_when_every_key_is_low() {
+        let pool = pool(KeySelection::RoundRobin, &[]);
+        pool.key("a").unwrap().restricted.store(true, Ordering::Relaxed);
+        assert!(!pool.restricted(1, None));
+        pool.key("default").unwrap().restricted.store(true, Ordering::Relaxed);
+        pool.key("b").unwrap().restricted.store(true, Ordering::Relaxed);
+        assert!(pool.restricted(1, None));
+        assert!(!pool.restricted(7, None));
+    }
+
+    #[test]
+    fn starts_the_spend_over_every_month() {
+        let month = crate::usage::month_start(deepseek::page::now());
+        let mut spent = (month, 2.5);
+        assert_eq!(*this_month(&mut spent), 2.5);
+        let mut spent = (month - 1, 2.5);
+        assert_eq!(*this_month(&mut spent), 0.0);
+        assert_eq!(spent.0, month);
+        let pool = pool(KeySelection::RoundRobin, &[("a", 4.0)]);
+        *pool.key("a").unwrap().spent.lock().unwrap() = (month - 1, 4.0);
+        pool.add_spend("a", 1.0);
+        assert_eq!(pool.key("a").unwrap().spent(), 1.0);
+    }
+}
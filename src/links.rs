@@ -0,0 +1,50 @@
+use teloxide::types::{Message, MessageEntityKind};
+
+/// Links beyond this many in one message are ignored
+pub const MAX_LINKS: usize = 3;
+/// How much of the linked pages is given to the model, shared between them
+pub const CONTEXT_CHARS: usize = 32000;
+
+pub const LINKS_PROMPT: &str = "The user has shared web pages. Their content is given below, each between <page> tags with its number, title and URL.\n{content}\nBased on these pages, respond to the user's message. Cite the pages you rely on by their numbers in square brackets, like [1] or [1][2], right after the statements they support. PLEASE ANSWER THE QUERY IN THE SAME LANGUAGE THAT IT'S ASKED!";
+/// Asked when a link is sent on its own
+pub const DEFAULT_QUESTION: &str = "Summarize this page.";
+
+/// The web links in a message, written out or behind text
+pub fn message_urls(msg: &Message) -> Vec<url::Url> {
+    let entities = msg.parse_entities().or(msg.parse_caption_entities()).unwrap_or_default();
+    let mut urls = Vec::new();
+    for entity in entities {
+        let link = match entity.kind() {
+            MessageEntityKind::Url => url::Url::parse(entity.text()).or_else(|_| url::Url::parse(&format!("https://{}", entity.text()))),
+            MessageEntityKind::TextLink { url } => Ok(url.to_owned()),
+            _ => continue,
+        };
+        if let Some(link) = link.ok().filter(|link| matches!(link.scheme(), "http" | "https")) {
+            if !urls.contains(&link) {
+                urls.push(link);
+            }
+        }
+    }
+    urls.truncate(MAX_LINKS);
+    urls
+}
+
+/// The links in `msg`, or in the message it replies to if it has none
+pub fn urls(msg: &Message) -> Vec<url::Url> {
+    let urls = message_urls(msg);
+    if urls.is_empty() {
+        msg.reply_to_message().map(message_urls).unwrap_or_default()
+    } else {
+        urls
+    }
+}
+
+/// `text` with the links written out in `msg` removed, leaving the question about them
+pub fn strip_urls(text: &str, msg: &Message) -> String {
+    let entities = msg.parse_entities().or(msg.parse_caption_entities()).unwrap_or_default();
+    let mut text = text.to_string();
+    for entity in entities.iter().filter(|entity| matches!(entity.kind(), MessageEntityKind::Url)) {
+        text = text.replace(entity.text(), "");
+    }
+    text.trim().to_string()
+}
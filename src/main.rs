@@ -1,10 +1,33 @@
 mod user;
 mod config;
+mod settings;
+mod markdown;
+mod answers;
+mod documents;
+mod index;
+mod links;
+mod menu;
+mod tasks;
+mod previews;
+mod access;
+mod usage;
+mod balance;
+mod keys;
 use deepseek::api::DeepSeekAPI;
 use deepseek::search;
 use reqwest::Client;
+use std::sync::Arc;
+use tokio::sync::Notify;
 use teloxide::payloads::EditMessageTextInlineSetters;
 use teloxide::payloads::SendMessageSetters;
+use teloxide::payloads::SendDocumentSetters;
+use teloxide::payloads::SendMediaGroupSetters;
+use teloxide::payloads::EditMessageTextSetters;
+use teloxide::payloads::AnswerCallbackQuerySetters;
+use teloxide::payloads::AnswerInlineQuerySetters;
+use teloxide::payloads::SendPhotoSetters;
+use teloxide::ApiError;
+use teloxide::requests::HasPayload;
 use teloxide::RequestError;
 use teloxide::types::*;
 use teloxide::prelude::*;
@@ -19,22 +42,58 @@ const MAX_TOKEN: u64 = 300;
 enum Command {
     #[command(description = "display this text")]
     Help,
+    #[command(description = "start chatting with the bot")]
+    Start(String),
     #[command(description = "test connectivity")]
     Die,
-    #[command(description = "get account information")]
+    #[command(description = "get the balance of the API keys answering here")]
     Info,
     #[command(description = "allow one user to query")]
     Grant,
+    #[command(description = "ask a question, searching the web if it helps")]
+    Ask(String),
+    #[command(description = "ask a question after searching the web")]
+    Search(String),
+    #[command(description = "ask a question without searching the web")]
+    NoSearch(String),
+    #[command(description = "think hard about a question with deepseek-r1")]
+    Think(String),
+    #[command(description = "let the bot answer in this group")]
+    Enable,
+    #[command(description = "stop the bot from answering in this group")]
+    Disable,
+    #[command(description = "list the documents shared in this chat")]
+    Docs,
+    #[command(description = "ask a question about the documents shared in this chat")]
+    AskDocs(String),
+    #[command(description = "forget a document by its number in /docs, or all of them")]
+    ForgetDoc(String),
+    #[command(description = "summarize a linked page, or answer a question about it")]
+    Tldr(String),
+    #[command(description = "set the system prompt of this chat, show it without arguments, or clear it")]
+    System(String),
+    #[command(description = "switch this chat to a persona, list them without arguments, or none to stop")]
+    Persona(String),
+    #[command(description = "choose the model, answer length, web search, reasoning display and language")]
+    Settings,
+    #[command(description = "stop your questions that are still being answered in this chat")]
+    Cancel,
+    #[command(description = "ask the superusers for permission to use the bot, with an optional message")]
+    Request(String),
+    #[command(description = "show the tokens and cost of your questions today and this month")]
+    Usage,
+    #[command(description = "show usage statistics of this month, with a daily cost chart if followed by chart")]
+    Stats(String),
 }
 
 macro_rules! retry_future {
     ($future:expr) => {{
         let mut result = $future.await;
-        if matches!(result, Err(_)) {
+        if result.is_err() {
             for i in 1..MAX_RETRY {
                 log::debug!("Retrying: {}/{MAX_RETRY}", i + 1);
                 let new_result = $future.await;
-                if matches!(new_result, Ok(_)) {
+                if new_result.is_ok() {
                     result = new_result;
                     break;
                 }
@@ -47,16 +106,82 @@ macro_rules! retry_future {
 /// ref:
 /// https://github.com/python-telegram-bot/python-telegram-bot/blob/4f255b6e21debd7ff5274400bf0d36e56bf169fa/telegram/helpers.py#L46
 fn escape_markdown(text: String) -> String {
-    // truncate the string to meet Telegram API requirement
-    let text = String::from_utf8_lossy(text.as_bytes().into_iter().cloned().take(2048).collect::<Vec<u8>>().as_slice()).to_string();
-    const ESCAPE_CHARS: &str = r"\_*[]()~`>#+-=|{}.!";
-    let escaped_pattern = regex::escape(ESCAPE_CHARS);
-    let re = regex::Regex::new(&format!("([{}])", escaped_pattern)).unwrap();
-    re.replace_all(&text, r"\$1").to_string()
+    markdown::escape(&text)
+}
+
+/// Whether Telegram refused a message because of its formatting
+fn is_entity_error(e: &RequestError) -> bool {
+    matches!(e, RequestError::Api(ApiError::CantParseEntities(_)))
 }
 
 
 async fn reply_to_message(bot: Bot, msg: Message, text: String) -> Result<String, RequestError> {
+    reply_markdown_to_message(bot, msg, escape_markdown(text.to_owned()), text, None).await
+}
+
+/// Replies with Markdown written by the model, converted to MarkdownV2 and split into as many
+/// messages as needed. Answers longer than `attachment_threshold` are sent as files instead.
+async fn reply_answer_to_message(bot: Bot, msg: Message, text: String) -> Result<String, RequestError> {
+    reply_answer_with_keyboard(bot, msg, text, None).await
+}
+
+/// Like `reply_answer_to_message`, with `reply_markup` attached to the last message
+async fn reply_answer_with_keyboard(bot: Bot, msg: Message, text: String, reply_markup: Option<InlineKeyboardMarkup>) -> Result<String, RequestError> {
+    let attachment_threshold = match config::get_config() {
+        Ok(config) => config.attachment_threshold,
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            0
+        }
+    };
+    if attachment_threshold > 0 && text.chars().count() > attachment_threshold {
+        return reply_answer_with_documents(bot, msg, text, reply_markup).await;
+    }
+    let parts = markdown::split_markdown(&text, markdown::MAX_MESSAGE_LENGTH);
+    let count = parts.len();
+    for (i, part) in parts.into_iter().enumerate() {
+        let reply_markup = if i + 1 == count { reply_markup.clone() } else { None };
+        reply_markdown_to_message(bot.to_owned(), msg.to_owned(), markdown::to_markdown_v2(&part), part, reply_markup).await?;
+    }
+    Ok(String::from("[void]"))
+}
+
+/// Posts the beginning of the answer, then attaches the full answer as a Markdown file along with
+/// a file for every code block.
+async fn reply_answer_with_documents(bot: Bot, msg: Message, text: String, reply_markup: Option<InlineKeyboardMarkup>) -> Result<String, RequestError> {
+    const SUMMARY_LENGTH: usize = 800;
+    const MAX_MEDIA_GROUP: usize = 10;
+    let code_blocks = markdown::code_blocks(&text);
+    let note = if code_blocks.is_empty() {
+        String::from("The full answer is attached.")
+    } else {
+        format!("The full answer and {} code block(s) are attached.", code_blocks.len())
+    };
+    let summary = format!("{}\n\n> {}", markdown::summary(&text, SUMMARY_LENGTH), note);
+    reply_markdown_to_message(bot.to_owned(), msg.to_owned(), markdown::to_markdown_v2(&summary), summary, reply_markup).await?;
+    let mut documents = vec![InputFile::memory(text.to_owned()).file_name("answer.md")];
+    for (index, block) in code_blocks.into_iter().enumerate() {
+        let file_name = format!("snippet_{}.{}", index + 1, markdown::file_extension(&block.language));
+        documents.push(InputFile::memory(block.code).file_name(file_name));
+    }
+    for group in documents.chunks(MAX_MEDIA_GROUP) {
+        let media = group.iter()
+            .map(|document| InputMedia::Document(InputMediaDocument::new(document.clone())))
+            .collect::<Vec<_>>();
+        if media.len() == 1 {
+            retry_future!(bot.send_document(msg.chat.id, group[0].clone())
+                .reply_parameters(ReplyParameters::new(msg.id)))?;
+        } else {
+            retry_future!(bot.send_media_group(msg.chat.id, media.clone())
+                .reply_parameters(ReplyParameters::new(msg.id)))?;
+        }
+    }
+    Ok(String::from("[void]"))
+}
+
+/// Like `reply_to_message`, but `text` is already formatted in MarkdownV2. If Telegram can't parse
+/// it, `fallback` is sent as plain text instead.
+async fn reply_markdown_to_message(bot: Bot, msg: Message, text: String, fallback: String, reply_markup: Option<InlineKeyboardMarkup>) -> Result<String, RequestError> {
     let reply_parameters = ReplyParameters {
         message_id: msg.id,
         chat_id: None,
@@ -66,217 +191,1828 @@ async fn reply_to_message(bot: Bot, msg: Message, text: String) -> Result<String
         quote_entities: None,
         quote_position: None,
     };
-    const MAX_RETRY: usize = 3;
-    retry_future!(bot.send_message(
-            msg.chat.id,
-            escape_markdown(text.to_owned()))
-        .parse_mode(ParseMode::MarkdownV2)
-        .reply_parameters(reply_parameters.clone()))?;
-    Ok(String::from("[void]"))
+    const MAX_RETRY: usize = 3;
+    match retry_future!(bot.send_message(
+            msg.chat.id,
+            text.to_owned())
+        .parse_mode(ParseMode::MarkdownV2)
+        .reply_parameters(reply_parameters.clone())
+        .with_payload_mut(|payload| payload.reply_markup = reply_markup.clone().map(ReplyMarkup::InlineKeyboard))) {
+        Err(e) if is_entity_error(&e) => {
+            log::warn!("Telegram rejected formatted message, sending plain text: {}", e);
+            retry_future!(bot.send_message(msg.chat.id, fallback.to_owned())
+                .reply_parameters(reply_parameters.clone())
+                .with_payload_mut(|payload| payload.reply_markup = reply_markup.clone().map(ReplyMarkup::InlineKeyboard)))?;
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(String::from("[void]"))
+}
+
+/// Replaces the text of an inline message with MarkdownV2 `text`, or plain `fallback` if Telegram can't parse it
+async fn edit_inline_markdown(bot: Bot, inline_message_id: String, text: String, fallback: String) -> Result<(), RequestError> {
+    match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), text.to_owned())
+        .parse_mode(ParseMode::MarkdownV2)
+    ) {
+        Err(e) if is_entity_error(&e) => {
+            log::warn!("Telegram rejected formatted message, sending plain text: {}", e);
+            retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), fallback.to_owned()))?;
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders the numbered source list shown below an answer, in Markdown.
+fn format_sources(sources: &[search::SearchResult]) -> String {
+    if sources.is_empty() {
+        return String::new();
+    }
+    let mut ret = String::from("**Sources:**\n\n");
+    for (index, source) in sources.iter().enumerate() {
+        let title = if !source.title.is_empty() {
+            source.title.split_whitespace().collect::<Vec<_>>().join(" ")
+        } else if !source.url.is_empty() {
+            source.url.to_owned()
+        } else {
+            format!("Search result {}", index + 1)
+        };
+        if source.url.is_empty() {
+            ret.push_str(&format!("{}. {}\n", index + 1, markdown::escape_commonmark(&title)));
+        } else {
+            ret.push_str(&format!("{}. [{}](<{}>)\n", index + 1, markdown::escape_commonmark(&title), source.url.replace('<', "%3C").replace('>', "%3E")));
+        }
+    }
+    ret
+}
+
+/// `full_answer` links to a private chat with the bot, for answers too long for one message
+fn generate_keyboard(full_answer: Option<url::Url>, actions: Vec<Vec<InlineKeyboardButton>>) -> InlineKeyboardMarkup {
+    let mut inline_keyboard = actions;
+    inline_keyboard.push(vec![
+        InlineKeyboardButton {
+            text: String::from("Try it!"),
+            kind: InlineKeyboardButtonKind::SwitchInlineQueryCurrentChat(String::new()),
+        }
+    ]);
+    if let Some(url) = full_answer {
+        inline_keyboard.push(vec![
+            InlineKeyboardButton {
+                text: String::from("Read full answer"),
+                kind: InlineKeyboardButtonKind::Url(url),
+            }
+        ]);
+    }
+    InlineKeyboardMarkup { inline_keyboard }
+}
+
+/// Prefix of `/start` parameters that open a stored answer
+const FULL_ANSWER_PREFIX: &str = "answer_";
+/// Callback data of the buttons on answers starts with this, followed by the action and the answer's id
+const ANSWER_CALLBACK_PREFIX: &str = "answer:";
+const CONTINUE_PROMPT: &str = "Continue your answer exactly where it stopped, without repeating anything.";
+/// Callback data of the Stop button, followed by the request's id in the task registry
+const STOP_CALLBACK_PREFIX: &str = "stop:";
+/// Finish reason of an answer stopped by the user
+const CANCELLED: &str = "cancelled";
+/// Id of the inline result that already holds the quick answer, so there is nothing left to do once it is sent
+const PREVIEW_RESULT_ID: &str = "preview";
+/// How long an inline preview may take before the results are shown without it, in milliseconds
+const PREVIEW_TIMEOUT: u64 = 8000;
+/// How much of the preview shows under its title in the results
+const PREVIEW_DESCRIPTION_CHARS: usize = 200;
+
+fn check_user_valid(user: User) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    match user::check_uid(user.id.0.to_string())? {
+        user::Role::Untrusted => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+fn inline_article(id: &str, title: &str, message_text: String, description: String, reply_markup: Option<InlineKeyboardMarkup>) -> InlineQueryResult {
+    InlineQueryResult::Article(InlineQueryResultArticle {
+        id: String::from(id),
+        title: String::from(title),
+        input_message_content: InputMessageContent::Text(InputMessageContentText {
+            message_text,
+            parse_mode: Some(ParseMode::MarkdownV2),
+            entities: None,
+            link_preview_options: None,
+        }),
+        reply_markup,
+        url: None,
+        hide_url: None,
+        description: Some(description),
+        thumbnail_url: Some(url::Url::parse("https://avatars.githubusercontent.com/u/148330874").unwrap()),
+        thumbnail_width: None,
+        thumbnail_height: None,
+    })
+}
+
+/// A short answer from deepseek-chat without searching, shown in the inline results
+async fn quick_answer(api: &DeepSeekAPI, user: &User, query: &str) -> Option<String> {
+    let preferences = chat_preferences(user.id.0.to_string());
+    let system_prompt = with_language(chat_system_prompt(user.id.0.to_string()), &preferences);
+    let messages = vec![
+        deepseek::types::DeepSeekMessage::system(system_prompt.to_owned()),
+        deepseek::types::DeepSeekMessage::user(query.to_string()),
+    ];
+    let max_tokens = preferences.max_tokens.unwrap_or(MAX_TOKEN).min(MAX_TOKEN);
+    let (api, key) = match api_for(api, Asker { user: user.id.0, chat: None }, UsageKind::Answer { searched: false }) {
+        Ok(api) => api,
+        Err(e) => {
+            log::error!("Unable to get preview from DeepSeek: {}", e);
+            return None;
+        }
+    };
+    let dialog = api.dialog(max_tokens, messages, deepseek::types::DeepSeekModel::DeepSeekChat);
+    match tokio::time::timeout(std::time::Duration::from_millis(PREVIEW_TIMEOUT), dialog).await {
+        Ok(Ok(reply)) => {
+            let answer = Answer {
+                reply: reply.content,
+                reasoning: None,
+                sources: Vec::new(),
+                tips: String::from("Quick answer from `deepseek-chat`."),
+                system_prompt,
+                finish_reason: reply.finish_reason,
+            };
+            Some(answer.markdown())
+        }
+        Ok(Err(e)) => {
+            log::error!("Unable to get preview from DeepSeek: {}", e);
+            drop_refused_key(&key, e.as_ref());
+            None
+        }
+        Err(_) => {
+            log::warn!("DeepSeek took too long to preview an answer");
+            None
+        }
+    }
+}
+
+async fn inline_handler(bot: Bot, msg: InlineQuery, api: DeepSeekAPI, previews: Arc<previews::PreviewCache>) -> ResponseResult<()> {
+    log::debug!("called inline_handler");
+    let cand: Vec<InlineQueryResult> = vec![
+        inline_article("chat", "Ask a question", escape_markdown(msg.query.to_owned()), msg.query.clone(), Some(generate_keyboard(None, Vec::new()))),
+        inline_article("think", "Think hard", escape_markdown(msg.query.to_owned()), msg.query.clone(), Some(generate_keyboard(None, Vec::new()))),
+    ];
+    let config = match config::get_config() {
+        Ok(config) => config.inline_preview,
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            config::InlinePreviewConfig::default()
+        }
+    };
+    match check_user_valid(msg.from.to_owned()) {
+        Ok(true) => (),
+        Ok(false) => {
+            // nothing to offer, besides a way to ask for access in a private chat
+            retry_future!(bot.answer_inline_query(msg.id.to_owned(), Vec::<InlineQueryResult>::new())
+                .button(InlineQueryResultsButton {
+                    text: String::from("Request access"),
+                    kind: InlineQueryResultsButtonKind::StartParameter(String::from(access::START_PARAMETER)),
+                })
+                .cache_time(0)
+                .is_personal(true))?;
+            return Ok(());
+        }
+        Err(e) => {
+            log::error!("Error checking user permission: {}", e);
+            return Ok(());
+        }
+    }
+    if !config.enabled || msg.query.trim().chars().count() < config.min_query_chars.max(1) {
+        retry_future!(bot.answer_inline_query(msg.id.to_owned(), cand.to_owned()))?;
+        return Ok(());
+    }
+    let user = msg.from.id.0;
+    previews.begin(user, &msg.id);
+    let preview = match previews.get(user, &msg.query) {
+        Some(preview) => Some(preview),
+        None => {
+            // Telegram sends a query for every keystroke, so wait until the user stops typing
+            tokio::time::sleep(std::time::Duration::from_millis(config.debounce)).await;
+            if !previews.is_latest(user, &msg.id) {
+                log::debug!("inline query typed over = {}", msg.query);
+                return Ok(());
+            }
+            if !previews.allow(user, config.hourly_limit) {
+                log::debug!("inline previews of user {} used up", user);
+                retry_future!(bot.answer_inline_query(msg.id.to_owned(), cand.to_owned()))?;
+                return Ok(());
+            }
+            let preview = quick_answer(&api, &msg.from, msg.query.trim()).await;
+            if let Some(preview) = &preview {
+                previews.insert(user, &msg.query, preview.to_owned());
+            }
+            preview
+        }
+    };
+    let Some(preview) = preview else {
+        retry_future!(bot.answer_inline_query(msg.id.to_owned(), cand.to_owned()))?;
+        return Ok(());
+    };
+    let text = format!("**Q: {}**\n\n{}", markdown::escape_commonmark(&msg.query.split_whitespace().collect::<Vec<_>>().join(" ")), preview);
+    let first = markdown::split_markdown(&text, markdown::MAX_MESSAGE_LENGTH).into_iter().next().unwrap_or_default();
+    let description = deepseek::page::truncate_chars(&preview.split_whitespace().collect::<Vec<_>>().join(" "), PREVIEW_DESCRIPTION_CHARS);
+    let with_preview = std::iter::once(inline_article(PREVIEW_RESULT_ID, "Quick answer", markdown::to_markdown_v2(&first), description, None))
+        .chain(cand.iter().cloned())
+        .collect::<Vec<_>>();
+    match retry_future!(bot.answer_inline_query(msg.id.to_owned(), with_preview.to_owned())
+        .cache_time(config.cache_time)
+        .is_personal(true)) {
+        // the static results don't depend on the model's formatting, so they can still be offered
+        Err(e) if is_entity_error(&e) => {
+            log::warn!("Telegram rejected the formatted preview, answering without it: {}", e);
+            retry_future!(bot.answer_inline_query(msg.id.to_owned(), cand.to_owned()))?;
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SearchMode {
+    /// let the model decide whether searching helps
+    Auto,
+    Always,
+    Never,
+}
+
+impl SearchMode {
+    fn from_preferences(preferences: &settings::Preferences) -> Self {
+        match preferences.search {
+            Some(settings::SearchPreference::Always) => SearchMode::Always,
+            Some(settings::SearchPreference::Never) => SearchMode::Never,
+            _ => SearchMode::Auto,
+        }
+    }
+}
+
+/// Who a question comes from, and where. Decides the API key and whom the usage is charged to.
+#[derive(Clone, Copy)]
+struct Asker {
+    user: u64,
+    /// `None` for inline messages
+    chat: Option<i64>,
+}
+
+impl Asker {
+    fn of(msg: &Message) -> Self {
+        Self { user: sender_id(msg), chat: Some(msg.chat.id.0) }
+    }
+}
+
+/// A question and how it is to be answered
+#[derive(Clone)]
+struct Question {
+    asker: Asker,
+    query: String,
+    model: deepseek::types::DeepSeekModel,
+    search_mode: SearchMode,
+    /// see `chat_system_prompt`
+    chat_prompt: String,
+    preferences: settings::Preferences,
+}
+
+/// A reply from DeepSeek, with what's needed to render and continue it
+#[derive(Clone)]
+struct Answer {
+    reply: String,
+    /// the model's chain of thought, when it is to be shown
+    reasoning: Option<String>,
+    sources: Vec<search::SearchResult>,
+    /// Markdown hint on how the answer was produced
+    tips: String,
+    /// the system prompt the answer was given with, search results included
+    system_prompt: String,
+    finish_reason: String,
+}
+
+/// An answer along with its question, kept so that the buttons on the answer can act on it
+#[derive(Clone)]
+struct AnswerContext {
+    question: Question,
+    answer: Answer,
+}
+
+impl Answer {
+    /// The reasoning, reply, sources and tips, with a note if the answer didn't finish normally, in Markdown
+    fn markdown(&self) -> String {
+        let mut ret = String::new();
+        if let Some(reasoning) = &self.reasoning {
+            ret.push_str("**Reasoning:**\n");
+            ret.push_str(&reasoning.trim().lines().map(|line| format!("> {}", line)).collect::<Vec<_>>().join("\n"));
+            ret.push_str("\n\n");
+        }
+        ret.push_str(&self.reply);
+        if !self.sources.is_empty() {
+            ret.push_str("\n\n");
+            ret.push_str(&format_sources(&self.sources));
+        }
+        let tips = match finish_note(&self.finish_reason) {
+            Some(note) if !self.tips.is_empty() => format!("{} {}", note, self.tips),
+            Some(note) => String::from(note),
+            None => self.tips.to_owned(),
+        };
+        if !tips.is_empty() {
+            ret.push_str(&format!("\n\n> {}", tips));
+        }
+        ret
+    }
+}
+
+/// The system prompt chosen for a chat with /system or /persona, empty if there is none
+fn chat_system_prompt(chat_id: String) -> String {
+    let settings = match settings::get_chat_settings(chat_id) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::error!("Error reading chat settings: {}", e);
+            return String::new();
+        }
+    };
+    if let Some(system_prompt) = settings.system_prompt {
+        return system_prompt;
+    }
+    let Some(persona) = settings.persona else {
+        return String::new();
+    };
+    match config::get_config() {
+        Ok(config) => config.personas.get(&persona).cloned().unwrap_or_else(|| {
+            log::error!("Persona {} is no longer configured", persona);
+            String::new()
+        }),
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            String::new()
+        }
+    }
+}
+
+/// Preferences set for a chat with /settings. A user's own are those of their private chat.
+fn chat_preferences(chat_id: String) -> settings::Preferences {
+    match settings::get_chat_settings(chat_id) {
+        Ok(settings) => settings.preferences,
+        Err(e) => {
+            log::error!("Error reading chat settings: {}", e);
+            settings::Preferences::default()
+        }
+    }
+}
+
+/// The sender's preferences, overridden in groups by the group's
+fn preferences_for(msg: &Message) -> settings::Preferences {
+    let preferences = msg.from.as_ref().map(|user| chat_preferences(user.id.0.to_string())).unwrap_or_default();
+    if is_group(msg) {
+        preferences.overlay(chat_preferences(msg.chat.id.0.to_string()))
+    } else {
+        preferences
+    }
+}
+
+/// Adds the answer language chosen in the preferences. It goes last, so it wins over prompts asking to answer
+/// in the language of the question.
+fn with_language(system_prompt: String, preferences: &settings::Preferences) -> String {
+    match &preferences.language {
+        Some(language) => merge_system_prompts(&system_prompt, &format!("Always write your answer in {}, whatever language the question is in.", language)),
+        None => system_prompt,
+    }
+}
+
+/// Puts the chat's own instructions before the context gathered for a question, such as search results
+fn merge_system_prompts(chat_prompt: &str, context: &str) -> String {
+    match (chat_prompt.trim().is_empty(), context.trim().is_empty()) {
+        (true, _) => context.to_string(),
+        (false, true) => chat_prompt.to_string(),
+        (false, false) => format!("{}\n\n{}", chat_prompt.trim(), context),
+    }
+}
+
+/// The system prompt with search results for `query`, the sources and a tip, if searching is called for. The
+/// search driver's API uses the key `key`, which is removed if DeepSeek refuses it.
+async fn search_context(search_driver: &search::SearchDriver, key: &str, query: &str, search_mode: SearchMode) -> (String, Vec<search::SearchResult>, String) {
+    let need_search = match search_mode {
+        SearchMode::Auto => retry_future!(search_driver.determine(query.to_owned())),
+        SearchMode::Always => Ok(true),
+        SearchMode::Never => Ok(false),
+    };
+    match need_search {
+        Ok(need_search) => {
+            if need_search {
+                log::debug!("Search invoked.");
+                let summary = retry_future!(search_driver.search_and_summary(query.to_owned()));
+                let tips = String::from("Searching invoked. The answer may contain information from the Internet.");
+                match summary {
+                    Ok(summary) => (summary.system_prompt, summary.sources, tips),
+                    Err(e) => {
+                        log::error!("Error when fetching system prompt: {}", e);
+                        drop_refused_key(key, e.as_ref());
+                        (String::new(), Vec::new(), tips)
+                    }
+                }
+            } else {
+                (String::new(), Vec::new(), String::new())
+            }
+        },
+        Err(e) => {
+            log::error!("Error when determining need_search: {}", e);
+            drop_refused_key(key, e.as_ref());
+            (String::new(), Vec::new(), String::new())
+        }
+    }
+}
+
+/// Streams a reply so that it can be stopped through `cancel`, in which case what was received so far is
+/// returned with the finish reason `CANCELLED`. The usage of a stopped reply is only known to `api.on_usage`.
+async fn generate(api: &DeepSeekAPI, max_tokens: u64, messages: Vec<deepseek::types::DeepSeekMessage>, model: deepseek::types::DeepSeekModel, cancel: &Notify) -> Result<deepseek::types::DeepSeekReply, Box<dyn std::error::Error + Send + Sync>> {
+    let partial = std::sync::Mutex::new((String::new(), String::new()));
+    let stream = api.dialog_stream(max_tokens, messages, model, |content, reasoning| {
+        let mut partial = partial.lock().unwrap();
+        partial.0.push_str(content);
+        partial.1.push_str(reasoning);
+    });
+    tokio::select! {
+        reply = stream => reply,
+        _ = cancel.notified() => {
+            let (content, reasoning) = partial.lock().unwrap().to_owned();
+            Ok(deepseek::types::DeepSeekReply {
+                content,
+                reasoning_content: Some(reasoning).filter(|reasoning| !reasoning.trim().is_empty()),
+                finish_reason: String::from(CANCELLED),
+                usage: deepseek::types::DeepSeekUsage::default(),
+            })
+        }
+    }
+}
+
+/// Whom requests for `asker` are queued as. Superusers go first.
+fn requester_for(api: &DeepSeekAPI, asker: Asker) -> deepseek::limiter::Requester {
+    let priority = match config::get_config() {
+        Ok(config) => config.superusers().contains(&asker.user.to_string()),
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            false
+        }
+    };
+    deepseek::limiter::Requester { user: asker.user, priority, on_position: api.requester.on_position.clone() }
+}
+
+/// What completions are made for, as told in the usage log
+#[derive(Clone, Copy)]
+enum UsageKind {
+    /// answering, with or without search results
+    Answer { searched: bool },
+    /// deciding whether to search, what for, and summarizing the results
+    Search,
+}
+
+/// Records the usage of each completion made for `user` with the key `key`
+fn usage_recorder(user: u64, key: String, kind: UsageKind) -> deepseek::api::UsageCallback {
+    Arc::new(move |model, usage, latency| record_usage(user, &key, model, usage, kind, latency))
+}
+
+/// The API with a key from the pool for `asker`, and the key's name. Its usage is recorded as `kind`.
+fn api_for(api: &DeepSeekAPI, asker: Asker, kind: UsageKind) -> Result<(DeepSeekAPI, String), Box<dyn std::error::Error + Send + Sync>> {
+    match keys::select(asker.user, asker.chat) {
+        Some(key) => Ok((DeepSeekAPI {
+            token: key.token,
+            requester: requester_for(api, asker),
+            on_usage: Some(usage_recorder(asker.user, key.name.to_owned(), kind)),
+            ..api.clone()
+        }, key.name)),
+        None => Err("no usable DeepSeek API key".into()),
+    }
+}
+
+/// Removes the key `name` from the pool if DeepSeek refused it for being wrong or out of balance
+fn drop_refused_key(name: &str, e: &(dyn std::error::Error + Send + Sync + 'static)) {
+    if let Some(e) = e.downcast_ref::<deepseek::api::DeepSeekStatusError>().filter(|e| matches!(e.status, 401 | 402)) {
+        log::error!("Removing API key {}: {}", name, e);
+        keys::remove(name);
+    }
+}
+
+/// Like `generate`, with a key from the pool for `asker`. A refused key is removed, so that retrying picks another.
+async fn generate_with_key(api: &DeepSeekAPI, max_tokens: u64, messages: Vec<deepseek::types::DeepSeekMessage>, model: deepseek::types::DeepSeekModel, cancel: &Notify, asker: Asker, searched: bool) -> Result<deepseek::types::DeepSeekReply, Box<dyn std::error::Error + Send + Sync>> {
+    let (api, key) = api_for(api, asker, UsageKind::Answer { searched })?;
+    match generate(&api, max_tokens, messages, model, cancel).await {
+        Ok(reply) => Ok(reply),
+        Err(e) => {
+            drop_refused_key(&key, e.as_ref());
+            Err(e)
+        }
+    }
+}
+
+/// What to tell the user about an answer that didn't finish normally
+fn finish_note(finish_reason: &str) -> Option<&'static str> {
+    match finish_reason {
+        "length" => Some("The answer reached the length limit and may be incomplete."),
+        "content_filter" => Some("The answer was cut short by DeepSeek's content filter."),
+        "insufficient_system_resource" => Some("DeepSeek ran short of resources and the answer may be incomplete. Please try again later."),
+        CANCELLED => Some("Stopped before the answer was complete."),
+        _ => None,
+    }
+}
+
+/// Generates a reply to `messages`, continuing it while it is cut off by the token limit, up to
+/// `max_continuations` times as configured. The usage of every completion is recorded for `asker`.
+async fn complete(api: &DeepSeekAPI, max_tokens: u64, messages: Vec<deepseek::types::DeepSeekMessage>, model: deepseek::types::DeepSeekModel, cancel: &Notify, asker: Asker, searched: bool) -> Result<deepseek::types::DeepSeekReply, Box<dyn std::error::Error + Send + Sync>> {
+    let model = if keys::restricted(asker.user, asker.chat) { deepseek::types::DeepSeekModel::DeepSeekChat } else { model };
+    let max_continuations = match config::get_config() {
+        Ok(config) => config.max_continuations,
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            0
+        }
+    };
+    let mut reply = retry_future!(generate_with_key(api, max_tokens, messages.clone(), model.clone(), cancel, asker, searched))?;
+    for i in 0..max_continuations {
+        // a reasoner that used up its tokens thinking has nothing to continue from
+        if reply.finish_reason != "length" || reply.content.trim().is_empty() {
+            break;
+        }
+        log::debug!("Answer cut off by the token limit, continuing: {}/{}", i + 1, max_continuations);
+        let mut continued = messages.clone();
+        continued.push(deepseek::types::DeepSeekMessage::assistant(reply.content.to_owned()));
+        continued.push(deepseek::types::DeepSeekMessage::user(String::from(CONTINUE_PROMPT)));
+        let next = retry_future!(generate_with_key(api, max_tokens, continued.clone(), model.clone(), cancel, asker, searched))?;
+        reply.content.push_str(&next.content);
+        reply.finish_reason = next.finish_reason;
+        reply.usage.add(&next.usage);
+    }
+    match reply.finish_reason.as_str() {
+        "content_filter" => log::warn!("Answer filtered by DeepSeek, user = {}, chat = {:?}, response = {}", asker.user, asker.chat, reply.content),
+        "insufficient_system_resource" => log::warn!("DeepSeek ran short of resources while answering"),
+        _ => (),
+    }
+    if reply.content.trim().is_empty() && reply.finish_reason != CANCELLED {
+        reply.content = String::from(deepseek::api::NO_RESPONSE);
+    }
+    Ok(reply)
+}
+
+fn record_usage(user: u64, key: &str, model: &deepseek::types::DeepSeekModel, usage: &deepseek::types::DeepSeekUsage, kind: UsageKind, latency: std::time::Duration) {
+    let record = usage::UsageRecord {
+        time: deepseek::page::now(),
+        user,
+        model: model.name().to_string(),
+        prompt_tokens: usage.prompt_tokens,
+        completion_tokens: usage.completion_tokens,
+        cache_hit_tokens: usage.prompt_cache_hit_tokens,
+        searched: matches!(kind, UsageKind::Answer { searched: true }),
+        searching: matches!(kind, UsageKind::Search),
+        latency: latency.as_millis() as u64,
+        key: key.to_string(),
+    };
+    match config::get_config() {
+        Ok(config) => keys::add_spend(key, config.pricing.cost(&record)),
+        Err(e) => log::error!("Error reading config: {}", e),
+    }
+    if let Err(e) = usage::record(&record) {
+        log::error!("Error recording usage: {}", e);
+    }
+}
+
+async fn answer_query(api: &DeepSeekAPI, search_driver: &search::SearchDriver, question: &Question, cancel: &Notify) -> Result<Answer, Box<dyn std::error::Error + Send + Sync>> {
+    let Question { asker, query, model, search_mode, chat_prompt, preferences } = question.to_owned();
+    let (search_api, key) = api_for(api, asker, UsageKind::Search)?;
+    let search_driver = &search::SearchDriver { api: search_api, ..search_driver.clone() };
+    let context = tokio::select! {
+        context = search_context(search_driver, &key, &query, search_mode) => Some(context),
+        _ = cancel.notified() => None,
+    };
+    let Some((system_prompt, sources, mut tips)) = context else {
+        return Ok(Answer {
+            reply: String::new(),
+            reasoning: None,
+            sources: Vec::new(),
+            tips: String::new(),
+            system_prompt: String::new(),
+            finish_reason: String::from(CANCELLED),
+        });
+    };
+    // only searching leaves a tip at this point
+    let searched = !tips.is_empty();
+    if matches!(model, deepseek::types::DeepSeekModel::DeepSeekReasoner) {
+        tips = if keys::restricted(asker.user, asker.chat) {
+            String::from("`deepseek-r1` is disabled while the balance is low, so `deepseek-chat` answered instead.")
+        } else {
+            String::from("This chat uses `deepseek-r1` model.")
+        };
+    }
+    let system_prompt = with_language(merge_system_prompts(&chat_prompt, &system_prompt), &preferences);
+    let max_tokens = preferences.max_tokens.unwrap_or(MAX_TOKEN);
+    let messages = vec![
+        deepseek::types::DeepSeekMessage::system(system_prompt.to_owned()),
+        deepseek::types::DeepSeekMessage::user(query.to_owned()),
+    ];
+    let reply = complete(api, max_tokens, messages, model.clone(), cancel, asker, searched).await?;
+    let reasoning = reply.reasoning_content.filter(|_| preferences.show_reasoning.unwrap_or_default());
+    Ok(Answer { reply: reply.content, reasoning, sources, tips, system_prompt, finish_reason: reply.finish_reason })
+}
+
+/// Asks the model to go on with an answer that was cut off, for `asker`. Returns only the continuation.
+async fn continue_answer(api: &DeepSeekAPI, context: &AnswerContext, cancel: &Notify, asker: Asker) -> Result<Answer, Box<dyn std::error::Error + Send + Sync>> {
+    let messages = vec![
+        deepseek::types::DeepSeekMessage::system(context.answer.system_prompt.to_owned()),
+        deepseek::types::DeepSeekMessage::user(context.question.query.to_owned()),
+        deepseek::types::DeepSeekMessage::assistant(context.answer.reply.to_owned()),
+        deepseek::types::DeepSeekMessage::user(String::from(CONTINUE_PROMPT)),
+    ];
+    let max_tokens = context.question.preferences.max_tokens.unwrap_or(MAX_TOKEN);
+    let reply = complete(api, max_tokens, messages, context.question.model.clone(), cancel, asker, false).await?;
+    let reasoning = reply.reasoning_content.filter(|_| context.question.preferences.show_reasoning.unwrap_or_default());
+    Ok(Answer {
+        reply: reply.content,
+        reasoning,
+        sources: context.answer.sources.to_owned(),
+        tips: context.answer.tips.to_owned(),
+        system_prompt: context.answer.system_prompt.to_owned(),
+        finish_reason: reply.finish_reason,
+    })
+}
+
+/// Stores `context` and returns the buttons acting on its answer
+fn answer_actions(contexts: &answers::AnswerStore<AnswerContext>, key: &str, context: AnswerContext) -> Vec<Vec<InlineKeyboardButton>> {
+    let id = contexts.insert(key, context.to_owned());
+    let button = |text: &str, action: &str| InlineKeyboardButton::callback(text, format!("{ANSWER_CALLBACK_PREFIX}{action}:{id}"));
+    let mut first = vec![button("Regenerate", "regenerate")];
+    // an answer stopped before anything was written, e.g. while searching, has nothing to continue from
+    let unfinished = context.answer.finish_reason == "length" || context.answer.finish_reason == CANCELLED;
+    if unfinished && !context.answer.reply.trim().is_empty() {
+        first.push(button("Continue", "continue"));
+    }
+    let mut second = Vec::new();
+    if !matches!(context.question.model, deepseek::types::DeepSeekModel::DeepSeekReasoner) {
+        second.push(button("Think harder", "think"));
+    }
+    if context.answer.sources.is_empty() {
+        second.push(button("Search the web", "search"));
+    }
+    [first, second].into_iter().filter(|row| !row.is_empty()).collect()
+}
+
+fn stop_keyboard(task: &tasks::TaskHandle) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![vec![InlineKeyboardButton::callback("Stop", format!("{STOP_CALLBACK_PREFIX}{}", task.id))]])
+}
+
+/// Replies to `msg` with a placeholder that can stop `task`, to be deleted once the answer is sent
+async fn send_placeholder(bot: &Bot, msg: &Message, task: &tasks::TaskHandle) -> Option<Message> {
+    match retry_future!(bot.send_message(msg.chat.id, "Thinking...")
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .reply_markup(stop_keyboard(task))
+    ) {
+        Ok(placeholder) => Some(placeholder),
+        Err(e) => {
+            log::error!("Error sending placeholder: {}", e);
+            None
+        }
+    }
+}
+
+/// The API with the place in the queue shown in `placeholder` while waiting for DeepSeek
+fn show_queue_position(api: &DeepSeekAPI, bot: &Bot, placeholder: Option<&Message>, task: &tasks::TaskHandle) -> DeepSeekAPI {
+    let Some(placeholder) = placeholder else {
+        return api.to_owned();
+    };
+    let (sender, mut receiver) = tokio::sync::watch::channel(0);
+    let (bot, placeholder, keyboard) = (bot.to_owned(), placeholder.to_owned(), stop_keyboard(task));
+    // the latest position only, once the previous edit is done
+    tokio::spawn(async move {
+        while receiver.changed().await.is_ok() {
+            let ahead = *receiver.borrow_and_update();
+            let text = if ahead == 0 { String::from("Thinking...") } else { format!("Waiting for DeepSeek, {} ahead in line...", ahead) };
+            if let Err(e) = bot.edit_message_text(placeholder.chat.id, placeholder.id, text).reply_markup(keyboard.clone()).await {
+                log::error!("Error updating placeholder: {}", e);
+            }
+        }
+    });
+    let on_position = Arc::new(move |ahead| {
+        let _ = sender.send(ahead);
+    });
+    DeepSeekAPI { requester: deepseek::limiter::Requester { on_position: Some(on_position), ..api.requester.clone() }, ..api.clone() }
+}
+
+async fn delete_placeholder(bot: &Bot, placeholder: Option<Message>) {
+    if let Some(placeholder) = placeholder {
+        if let Err(e) = bot.delete_message(placeholder.chat.id, placeholder.id).await {
+            log::error!("Error deleting placeholder: {}", e);
+        }
+    }
+}
+
+/// Shows an answer in an inline message, along with a link to the full answer if it doesn't fit
+async fn show_inline_answer(bot: &Bot, me: &Me, answers: &answers::AnswerStore, inline_message_id: String, query: &str, answer: &str, actions: Vec<Vec<InlineKeyboardButton>>) -> Result<(), RequestError> {
+    let text = format!("**Q: {}**\n\n{}", markdown::escape_commonmark(&query.split_whitespace().collect::<Vec<_>>().join(" ")), answer);
+    let parts = markdown::split_markdown(&text, markdown::MAX_MESSAGE_LENGTH);
+    let full_answer = if parts.len() > 1 {
+        let id = answers.insert(&inline_message_id, text.to_owned());
+        let mut url = me.tme_url();
+        url.query_pairs_mut().append_pair("start", &format!("{FULL_ANSWER_PREFIX}{id}"));
+        Some(url)
+    } else {
+        None
+    };
+    let first = parts.into_iter().next().unwrap_or_default();
+    edit_inline_markdown(bot.to_owned(), inline_message_id.to_owned(), markdown::to_markdown_v2(&first), first.to_owned()).await?;
+    let keyboard = generate_keyboard(full_answer, actions);
+    match retry_future!(bot.edit_message_reply_markup_inline(inline_message_id.to_owned()).reply_markup(keyboard.clone())) {
+        Ok(_) => (),
+        Err(e) => log::error!("Error updating inline button: {}", e),
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn inline_result_handler(bot: Bot, msg: ChosenInlineResult, me: Me, api: DeepSeekAPI, search_driver: search::SearchDriver, answers: Arc<answers::AnswerStore>, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
+    log::debug!("called inline_result_handler");
+    let _ = api.get_balance().await;  // warm-up connection
+    let query_type = msg.result_id;
+    if query_type == PREVIEW_RESULT_ID {
+        return Ok(());
+    }
+    let query = msg.query;
+    let inline_message_id = msg.inline_message_id.unwrap_or_default();
+    log::debug!("inline message id = {}", inline_message_id.to_owned());
+    match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), format!("{}\n\n_Asking question\\.\\.\\._", escape_markdown(query.to_owned())))
+        .parse_mode(ParseMode::MarkdownV2)
+    ) {
+        Ok(_) => {
+            let role = check_user_valid(msg.from.to_owned());
+            match role {
+                Ok(valid) => {
+                    if !valid {
+                        match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), format!("*Q: {}*\n\n_User does not have permission\\._", escape_markdown(query.to_owned())))
+                            .parse_mode(ParseMode::MarkdownV2)
+                        ) {
+                            Ok(_) => (),
+                            Err(e) => log::error!("Error updating inline hint: {}", e),
+                        }
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    log::error!("Error when checking role: {}", e);
+                    return Ok(());
+                }
+            }
+            let preferences = chat_preferences(msg.from.id.0.to_string());
+            let (model, search_mode) = if query_type == "think" {
+                (deepseek::types::DeepSeekModel::DeepSeekReasoner, SearchMode::Never)
+            } else {
+                (preferences.model(), SearchMode::from_preferences(&preferences))
+            };
+            let question = Question {
+                asker: Asker { user: msg.from.id.0, chat: None },
+                query: query.to_owned(),
+                model,
+                search_mode,
+                chat_prompt: chat_system_prompt(msg.from.id.0.to_string()),
+                preferences,
+            };
+            let task = tasks.start(question.asker.user, None);
+            match retry_future!(bot.edit_message_reply_markup_inline(inline_message_id.to_owned()).reply_markup(stop_keyboard(&task))) {
+                Ok(_) => (),
+                Err(e) => log::error!("Error adding stop button: {}", e),
+            }
+            match answer_query(&api, &search_driver, &question, &task.cancel).await {
+                Ok(answer) => {
+                    log::debug!("received response from DeepSeek = {}", escape_markdown(answer.reply.to_owned()));
+                    let text = answer.markdown();
+                    let actions = answer_actions(&contexts, &inline_message_id, AnswerContext { question, answer });
+                    match show_inline_answer(&bot, &me, &answers, inline_message_id.to_owned(), &query, &text, actions).await {
+                        Ok(_) => log::debug!("sent response = {}", escape_markdown(text)),
+                        Err(e) => log::error!("Error sending response: {}", e)
+                    }
+                }
+                Err(e) => log::error!("Unable to get response from DeepSeek: {}", e)
+            }
+        }
+        Err(e) => log::error!("Error when updating inline hint: {}", e)
+    }
+    Ok(())
+}
+
+/// For a group message, returns the question if the bot is addressed: @mentioned, called with a
+/// trigger prefix, or replied to. The mention or prefix is stripped.
+fn addressed_query(text: &str, msg: &Message, me: &Me, trigger_prefixes: &[String]) -> Option<String> {
+    // the bot's username doesn't change while it runs
+    static MENTION: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let mention = MENTION.get_or_init(|| regex::Regex::new(&format!(r"(?i)@{}\b", regex::escape(me.username()))).unwrap());
+    if mention.is_match(text) {
+        return Some(mention.replace_all(text, "").trim().to_string());
+    }
+    if let Some(prefix) = trigger_prefixes.iter().find(|prefix| !prefix.is_empty() && text.starts_with(prefix.as_str())) {
+        return Some(text[prefix.len()..].trim().to_string());
+    }
+    let replied_to_bot = msg.reply_to_message()
+        .and_then(|replied| replied.from.as_ref())
+        .is_some_and(|user| user.id == me.id);
+    if replied_to_bot {
+        return Some(text.trim().to_string());
+    }
+    None
+}
+
+/// Id of the user who sent `msg`, 0 for channel posts
+fn sender_id(msg: &Message) -> u64 {
+    msg.from.as_ref().map(|user| user.id.0).unwrap_or_default()
+}
+
+fn is_group(msg: &Message) -> bool {
+    msg.chat.is_group() || msg.chat.is_supergroup()
+}
+
+/// Whether the bot has been turned off in this chat with /disable
+fn chat_disabled(msg: &Message) -> bool {
+    match settings::get_chat_settings(msg.chat.id.0.to_string()) {
+        Ok(settings) => !settings.enabled,
+        Err(e) => {
+            log::error!("Error reading chat settings: {}", e);
+            false
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn chat_handler(bot: Bot, msg: Message, me: Me, api: DeepSeekAPI, search_driver: search::SearchDriver, index: Arc<index::DocumentIndex>, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
+    log::debug!("called chat_handler");
+    if msg.via_bot.is_some() {
+        return Ok(())
+    }
+    // a document's caption is its question, and a reply to a document asks about it
+    let Some(text) = msg.text().or(msg.document().map(|_| msg.caption().unwrap_or_default())) else {
+        return Ok(());
+    };
+    let document = msg.document().or(msg.reply_to_message().and_then(|replied| replied.document()));
+    let text = if is_group(&msg) {
+        if chat_disabled(&msg) {
+            return Ok(());
+        }
+        let trigger_prefixes = match config::get_config() {
+            Ok(config) => config.group_trigger_prefixes,
+            Err(e) => {
+                log::error!("Error reading config: {}", e);
+                Vec::new()
+            }
+        };
+        match addressed_query(text, &msg, &me, &trigger_prefixes) {
+            Some(text) => text,
+            None => return Ok(()),
+        }
+    } else {
+        text.to_string()
+    };
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    if let Some(document) = document {
+        let question = if text.trim().is_empty() { String::from(documents::DEFAULT_QUESTION) } else { text };
+        log::debug!("Received question about document = {}", question);
+        let _ = bot.send_chat_action(msg.chat.id, ChatAction::Typing).await;
+        let response = document_reply(&bot, &api, &index, &msg.chat.id.0.to_string(), document, question, &preferences_for(&msg), Asker::of(&msg)).await;
+        match retry_future!(reply_answer_to_message(bot.to_owned(), msg.to_owned(), response.to_owned())) {
+            Ok(msg) => log::debug!("sent response = {}", msg),
+            Err(e) => log::error!("Error sending response: {}", e),
+        }
+        return Ok(());
+    }
+    let urls = links::message_urls(&msg);
+    if !urls.is_empty() {
+        let question = links::strip_urls(&text, &msg);
+        let question = if question.is_empty() { String::from(links::DEFAULT_QUESTION) } else { question };
+        log::debug!("Received question about {} links = {}", urls.len(), question);
+        let _ = bot.send_chat_action(msg.chat.id, ChatAction::Typing).await;
+        let response = link_reply(&api, &search_driver, &urls, question, chat_system_prompt(msg.chat.id.0.to_string()), &preferences_for(&msg), Asker::of(&msg)).await;
+        match retry_future!(reply_answer_to_message(bot.to_owned(), msg.to_owned(), response.to_owned())) {
+            Ok(msg) => log::debug!("sent response = {}", msg),
+            Err(e) => log::error!("Error sending response: {}", e),
+        }
+        return Ok(());
+    }
+    let query = question_from(&msg, text);
+    if query.is_empty() {
+        return Ok(());
+    }
+    log::debug!("Received msg = {}", query);
+    let mut response = String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.");
+    let mut actions = None;
+    let preferences = preferences_for(&msg);
+    let question = Question {
+        asker: Asker::of(&msg),
+        query: query.to_owned(),
+        model: preferences.model(),
+        search_mode: SearchMode::from_preferences(&preferences),
+        chat_prompt: chat_system_prompt(msg.chat.id.0.to_string()),
+        preferences,
+    };
+    let task = tasks.start(question.asker.user, Some(msg.chat.id.0));
+    let placeholder = send_placeholder(&bot, &msg, &task).await;
+    let api = show_queue_position(&api, &bot, placeholder.as_ref(), &task);
+    match answer_query(&api, &search_driver, &question, &task.cancel).await {
+        Ok(answer) => {
+            log::debug!("received response from DeepSeek = {}", escape_markdown(answer.reply.to_owned()));
+            response = answer.markdown();
+            actions = Some(InlineKeyboardMarkup::new(answer_actions(&contexts, &query, AnswerContext { question, answer })));
+        },
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+        },
+    }
+    drop(task);
+    match retry_future!(reply_answer_with_keyboard(bot.to_owned(), msg.to_owned(), response.to_owned(), actions.clone())) {
+        Ok(msg) => log::debug!("sent response = {}", msg),
+        Err(e) => log::error!("Error sending response: {}", e),
+    }
+    delete_placeholder(&bot, placeholder).await;
+
+    Ok(())
+}
+
+/// Answers `question` from the passages of documents retrieved for it
+async fn answer_from_passages(api: &DeepSeekAPI, retrieval: index::Retrieval, question: String, chat_prompt: String, preferences: &settings::Preferences, asker: Asker) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let content = retrieval.passages.iter()
+        .map(|passage| format!("<passage document=\"{}\" part=\"{}\">\n{}\n</passage>", passage.document, passage.number, passage.text))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let system = with_language(merge_system_prompts(&chat_prompt, &documents::PASSAGES_PROMPT.replace("{content}", &content)), preferences);
+    let max_tokens = preferences.max_tokens.unwrap_or(MAX_TOKEN);
+    let messages = vec![deepseek::types::DeepSeekMessage::system(system), deepseek::types::DeepSeekMessage::user(question)];
+    let reply = complete(api, max_tokens, messages, preferences.model(), &Notify::new(), asker, false).await?;
+    let mut reply = match finish_note(&reply.finish_reason) {
+        Some(note) => format!("{}\n\n> {}", reply.content, note),
+        None => reply.content,
+    };
+    if !retrieval.complete {
+        reply.push_str("\n\n> The documents are long, so only the passages most relevant to the question were read.");
+    }
+    Ok(reply)
+}
+
+/// Indexes `document` for the chat unless it already is, or explains why it couldn't
+async fn index_document(bot: &Bot, index: &Arc<index::DocumentIndex>, chat_id: &str, document: &Document) -> Result<(), String> {
+    if index.contains(chat_id, &document.file.unique_id) {
+        return Ok(());
+    }
+    let name = document.file_name.to_owned().unwrap_or_else(|| String::from("document"));
+    if document.file.size > documents::MAX_DOCUMENT_SIZE {
+        return Err(format!("{} is too large, only files up to {} MB can be read.", markdown::escape_commonmark(&name), documents::MAX_DOCUMENT_SIZE / 1024 / 1024));
+    }
+    let content = match retry_future!(documents::download(bot, document)) {
+        Ok(content) => content,
+        Err(e) => {
+            log::error!("Error downloading {}: {}", name, e);
+            return Err(format!("{} could not be downloaded.", markdown::escape_commonmark(&name)));
+        }
+    };
+    let text = match documents::extract_text(document, content).await {
+        Ok(text) => text,
+        Err(e) => {
+            log::error!("Error extracting text from {}: {}", name, e);
+            return Err(format!("No text could be read from {}. Plain text, Markdown, source code, HTML, PDF and DOCX files are supported.", markdown::escape_commonmark(&name)));
+        }
+    };
+    if text.is_empty() {
+        return Err(format!("{} doesn't contain any text.", markdown::escape_commonmark(&name)));
+    }
+    index.insert(chat_id, &document.file.unique_id, &name, &text);
+    if let Err(e) = index.save().await {
+        log::error!("Error saving document index: {}", e);
+    }
+    Ok(())
+}
+
+/// Indexes `document` and answers `question` about it, or explains why it couldn't
+#[allow(clippy::too_many_arguments)]
+async fn document_reply(bot: &Bot, api: &DeepSeekAPI, index: &Arc<index::DocumentIndex>, chat_id: &str, document: &Document, question: String, preferences: &settings::Preferences, asker: Asker) -> String {
+    if let Err(reason) = index_document(bot, index, chat_id, document).await {
+        return reason;
+    }
+    let retrieval = index.retrieve(chat_id, Some(&document.file.unique_id), &question, documents::CONTEXT_CHARS);
+    match answer_from_passages(api, retrieval, question, chat_system_prompt(chat_id.to_string()), preferences, asker).await {
+        Ok(reply) => {
+            log::debug!("received response from DeepSeek = {}", escape_markdown(reply.to_owned()));
+            reply
+        }
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+            String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.")
+        }
+    }
+}
+
+/// Fetches the pages at `urls` and answers `question` about them, citing them, or explains why it couldn't
+async fn link_reply(api: &DeepSeekAPI, search_driver: &search::SearchDriver, urls: &[url::Url], question: String, chat_prompt: String, preferences: &settings::Preferences, asker: Asker) -> String {
+    let max_chars = links::CONTEXT_CHARS / urls.len().max(1);
+    let mut sources = Vec::new();
+    for url in urls {
+        match deepseek::page::fetch_page_cached(url.as_str(), max_chars, search_driver.cache.as_deref()).await {
+            Some(page) => sources.push(search::SearchResult {
+                url: url.to_string(),
+                title: if page.title.is_empty() { url.to_string() } else { page.title },
+                content: deepseek::page::truncate_chars(&page.text, max_chars),
+                fetched_at: deepseek::page::now(),
+                ..Default::default()
+            }),
+            None => log::error!("Error fetching {}", url),
+        }
+    }
+    if sources.is_empty() {
+        return String::from("The linked page could not be opened.");
+    }
+    let content = sources.iter()
+        .enumerate()
+        .map(|(i, source)| format!("<page number=\"{}\" title=\"{}\" url=\"{}\">\n{}\n</page>", i + 1, source.title, source.url, source.content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let system = with_language(merge_system_prompts(&chat_prompt, &links::LINKS_PROMPT.replace("{content}", &content)), preferences);
+    let max_tokens = preferences.max_tokens.unwrap_or(MAX_TOKEN);
+    let messages = vec![deepseek::types::DeepSeekMessage::system(system.to_owned()), deepseek::types::DeepSeekMessage::user(question)];
+    match complete(api, max_tokens, messages, preferences.model(), &Notify::new(), asker, false).await {
+        Ok(reply) => {
+            log::debug!("received response from DeepSeek = {}", escape_markdown(reply.content.to_owned()));
+            Answer { reply: reply.content, reasoning: None, sources, tips: String::new(), system_prompt: system, finish_reason: reply.finish_reason }.markdown()
+        }
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+            String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.")
+        }
+    }
+}
+
+/// Replies with a permission notice and returns `false` if the sender isn't trusted
+async fn ensure_trusted(bot: Bot, msg: Message) -> bool {
+    if let Some(user) = msg.to_owned().from {
+        match check_user_valid(user.to_owned()) {
+            Ok(valid) => {
+                if !valid {
+                    let notice = untrusted_notice(&bot, &msg, &user).await;
+                    match retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from(notice))) {
+                        Ok(_) => (),
+                        Err(e) => log::error!("Error sending permission information: {}", e),
+                    }
+                }
+                valid
+            }
+            Err(e) => {
+                log::error!("Error checking user permission: {}", e);
+                false
+            }
+        }
+    } else {
+        false
+    }
+}
+
+/// What to tell an untrusted user. The first time they write to the bot privately, access is requested for them.
+async fn untrusted_notice(bot: &Bot, msg: &Message, user: &User) -> &'static str {
+    match access::get_access_request(user.id.0.to_string()) {
+        Ok(None) if msg.chat.is_private() => match send_access_request(bot, user, msg.text().unwrap_or_default()).await {
+            Ok(()) => "You don't have permission to use the bot yet, so it has been requested for you. You will be told when it is decided.",
+            Err(e) => {
+                log::error!("Error sending access request: {}", e);
+                "User doesn't have permission. Use /request to ask for it."
+            }
+        },
+        Ok(Some(request)) if request.status == access::RequestStatus::Pending => "User doesn't have permission yet. Your request is waiting for a decision.",
+        Ok(_) => "User doesn't have permission. Use /request to ask for it.",
+        Err(e) => {
+            log::error!("Error reading access requests: {}", e);
+            "User doesn't have permission."
+        }
+    }
+}
+
+/// The text of the message being replied to, if any
+fn quoted_text(msg: &Message) -> Option<String> {
+    msg.reply_to_message()
+        .and_then(|replied| replied.text().or(replied.caption()))
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// The question in `text` with the replied-to message attached as quoted context, or just the
+/// replied-to message if `text` is empty
+fn question_from(msg: &Message, text: String) -> String {
+    let text = text.trim();
+    match quoted_text(msg) {
+        Some(quoted) if text.is_empty() => quoted,
+        Some(quoted) => format!("{text}\n\nThe question above refers to this quoted message:\n\"\"\"\n{quoted}\n\"\"\""),
+        None => text.to_string(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn ask_command(bot: Bot, msg: Message, args: String, api: DeepSeekAPI, search_driver: search::SearchDriver, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>, model: deepseek::types::DeepSeekModel, search_mode: SearchMode) -> ResponseResult<()> {
+    if is_group(&msg) && chat_disabled(&msg) {
+        return Ok(());
+    }
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let query = question_from(&msg, args);
+    if query.is_empty() {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Please write a question after the command, or reply to a message with it.")))?;
+        return Ok(());
+    }
+    let question = Question {
+        asker: Asker::of(&msg),
+        query: query.to_owned(),
+        model,
+        search_mode,
+        chat_prompt: chat_system_prompt(msg.chat.id.0.to_string()),
+        preferences: preferences_for(&msg),
+    };
+    let task = tasks.start(question.asker.user, Some(msg.chat.id.0));
+    let placeholder = send_placeholder(&bot, &msg, &task).await;
+    let api = show_queue_position(&api, &bot, placeholder.as_ref(), &task);
+    let (response, actions) = match answer_query(&api, &search_driver, &question, &task.cancel).await {
+        Ok(answer) => (answer.markdown(), Some(InlineKeyboardMarkup::new(answer_actions(&contexts, &query, AnswerContext { question, answer })))),
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+            (String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details."), None)
+        }
+    };
+    drop(task);
+    match retry_future!(reply_answer_with_keyboard(bot.to_owned(), msg.to_owned(), response.to_owned(), actions.clone())) {
+        Ok(msg) => log::debug!("sent response = {}", msg),
+        Err(e) => log::error!("Error sending response: {}", e),
+    }
+    delete_placeholder(&bot, placeholder).await;
+    Ok(())
+}
+
+/// Answers a question from all the documents indexed in the chat
+async fn ask_documents_command(bot: Bot, msg: Message, args: String, api: DeepSeekAPI, index: Arc<index::DocumentIndex>) -> ResponseResult<()> {
+    if is_group(&msg) && chat_disabled(&msg) {
+        return Ok(());
+    }
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let chat_id = msg.chat.id.0.to_string();
+    if index.documents(&chat_id).is_empty() {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("No documents have been shared in this chat yet. Send one first.")))?;
+        return Ok(());
+    }
+    let question = args.trim().to_string();
+    if question.is_empty() {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Please write a question after the command.")))?;
+        return Ok(());
+    }
+    let _ = bot.send_chat_action(msg.chat.id, ChatAction::Typing).await;
+    let retrieval = index.retrieve(&chat_id, None, &question, documents::CONTEXT_CHARS);
+    let response = match answer_from_passages(&api, retrieval, question, chat_system_prompt(chat_id.to_owned()), &preferences_for(&msg), Asker::of(&msg)).await {
+        Ok(reply) => reply,
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+            String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.")
+        }
+    };
+    match retry_future!(reply_answer_to_message(bot.to_owned(), msg.to_owned(), response.to_owned())) {
+        Ok(msg) => log::debug!("sent response = {}", msg),
+        Err(e) => log::error!("Error sending response: {}", e),
+    }
+    Ok(())
+}
+
+async fn tldr_command(bot: Bot, msg: Message, args: String, api: DeepSeekAPI, search_driver: search::SearchDriver) -> ResponseResult<()> {
+    if is_group(&msg) && chat_disabled(&msg) {
+        return Ok(());
+    }
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let urls = links::urls(&msg);
+    if urls.is_empty() {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Please write a link after the command, or reply to a message with one.")))?;
+        return Ok(());
+    }
+    let question = links::strip_urls(&args, &msg);
+    let question = if question.is_empty() { String::from(links::DEFAULT_QUESTION) } else { question };
+    let _ = bot.send_chat_action(msg.chat.id, ChatAction::Typing).await;
+    let response = link_reply(&api, &search_driver, &urls, question, chat_system_prompt(msg.chat.id.0.to_string()), &preferences_for(&msg), Asker::of(&msg)).await;
+    match retry_future!(reply_answer_to_message(bot.to_owned(), msg.to_owned(), response.to_owned())) {
+        Ok(msg) => log::debug!("sent response = {}", msg),
+        Err(e) => log::error!("Error sending response: {}", e),
+    }
+    Ok(())
+}
+
+async fn list_documents(bot: Bot, msg: Message, index: Arc<index::DocumentIndex>) -> ResponseResult<()> {
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let documents = index.documents(&msg.chat.id.0.to_string());
+    let reply = if documents.is_empty() {
+        String::from("No documents have been shared in this chat yet.")
+    } else {
+        let mut reply = String::from("Documents in this chat:");
+        for (i, (name, chars)) in documents.iter().enumerate() {
+            reply.push_str(&format!("\n{}. {} ({} characters)", i + 1, name, chars));
+        }
+        reply
+    };
+    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+    Ok(())
+}
+
+async fn forget_document(bot: Bot, msg: Message, args: String, index: Arc<index::DocumentIndex>) -> ResponseResult<()> {
+    if !ensure_chat_admin(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let chat_id = msg.chat.id.0.to_string();
+    let args = args.trim();
+    let reply = if args.eq_ignore_ascii_case("all") {
+        format!("Forgot {} documents.", index.clear(&chat_id))
+    } else {
+        match args.parse::<usize>().ok().and_then(|number| index.remove(&chat_id, number)) {
+            Some(name) => format!("Forgot {}.", name),
+            None => String::from("Please give the number of a document as listed by /docs, or all."),
+        }
+    };
+    if let Err(e) = index.save().await {
+        log::error!("Error saving document index: {}", e);
+    }
+    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+    Ok(())
 }
 
-fn generate_keyboard() -> InlineKeyboardMarkup {
-    InlineKeyboardMarkup {
-        inline_keyboard: vec![vec![
-            InlineKeyboardButton {
-                text: format!("Try it!"),
-                kind: InlineKeyboardButtonKind::SwitchInlineQueryCurrentChat(String::new()),
-            }
-        ]],
+/// Replies with a notice and returns `false` unless the sender may change the chat's settings: a group
+/// administrator or the superuser in groups, a trusted user in private chats
+async fn ensure_chat_admin(bot: Bot, msg: Message) -> bool {
+    if !is_group(&msg) {
+        return ensure_trusted(bot, msg).await;
     }
+    let Some(user) = msg.to_owned().from else {
+        return false;
+    };
+    if !is_chat_admin(&bot, &msg.chat, &user).await {
+        match retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Only group administrators can do this."))) {
+            Ok(_) => (),
+            Err(e) => log::error!("Error sending permission information: {}", e),
+        }
+        return false;
+    }
+    true
 }
 
-fn check_user_valid(user: User) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
-    match user::check_uid(user.id.0.to_string())? {
-        user::Role::Untrusted => Ok(false),
-        _ => Ok(true),
+/// Whether `user` is a group administrator or the superuser in a group, or trusted in a private chat
+async fn is_chat_admin(bot: &Bot, chat: &Chat, user: &User) -> bool {
+    if !(chat.is_group() || chat.is_supergroup()) {
+        return check_user_valid(user.to_owned()).unwrap_or_else(|e| {
+            log::error!("Error checking user permission: {}", e);
+            false
+        });
+    }
+    if matches!(user::check_uid(user.id.0.to_string()), Ok(user::Role::SuperUser)) {
+        return true;
+    }
+    match retry_future!(bot.get_chat_member(chat.id, user.id)) {
+        Ok(member) => member.is_privileged(),
+        Err(e) => {
+            log::error!("Error fetching chat member: {}", e);
+            false
+        }
     }
 }
 
-async fn inline_handler(bot: Bot, msg: InlineQuery) -> ResponseResult<()> {
-    log::debug!("called inline_handler");
-    let cand: Vec<InlineQueryResult> = vec![
-        InlineQueryResult::Article(InlineQueryResultArticle {
-            id: format!("chat"),
-            title: format!("Ask a question"),
-            input_message_content: InputMessageContent::Text(InputMessageContentText {
-                message_text: escape_markdown(msg.query.to_owned()),
-                parse_mode: Some(ParseMode::MarkdownV2),
-                entities: None,
-                link_preview_options: None,
-            }),
-            reply_markup: Some(generate_keyboard()),
-            url: None,
-            hide_url: None,
-            description: Some(msg.query.clone()),
-            thumbnail_url: Some(url::Url::parse("https://avatars.githubusercontent.com/u/148330874").unwrap()),
-            thumbnail_width: None,
-            thumbnail_height: None,
-        }),
-    
-        InlineQueryResult::Article(InlineQueryResultArticle {
-            id: format!("think"),
-            title: format!("Think hard"),
-            input_message_content: InputMessageContent::Text(InputMessageContentText {
-                message_text: escape_markdown(msg.query.to_owned()),
-                parse_mode: Some(ParseMode::MarkdownV2),
-                entities: None,
-                link_preview_options: None,
-            }),
-            reply_markup: Some(generate_keyboard()),
-            url: None,
-            hide_url: None,
-            description: Some(msg.query.clone()),
-            thumbnail_url: Some(url::Url::parse("https://avatars.githubusercontent.com/u/148330874").unwrap()),
-            thumbnail_width: None,
-            thumbnail_height: None,
-        }),
-    ];
-    retry_future!(bot.answer_inline_query(msg.id.to_owned(), cand.to_owned()))?;
+fn settings_title(chat: &Chat) -> &'static str {
+    if chat.is_group() || chat.is_supergroup() {
+        "Settings of this group, for everyone's questions here:"
+    } else {
+        "Your settings, also used for your inline queries:"
+    }
+}
+
+async fn settings_command(bot: Bot, msg: Message) -> ResponseResult<()> {
+    let preferences = chat_preferences(msg.chat.id.0.to_string());
+    retry_future!(bot.send_message(msg.chat.id, menu::describe(settings_title(&msg.chat), &preferences, MAX_TOKEN))
+        .reply_parameters(ReplyParameters::new(msg.id))
+        .reply_markup(menu::main_menu(&preferences, MAX_TOKEN)))?;
     Ok(())
 }
 
-async fn inline_result_handler(bot: Bot, msg: ChosenInlineResult, api: DeepSeekAPI) -> ResponseResult<()> {
-    log::debug!("called callback_handler");
-    let _ = api.get_balance().await;  // warm-up connection
-    let model = if msg.result_id == "think" {
-        deepseek::types::DeepSeekModel::DeepSeekReasoner
-    } else {
-        deepseek::types::DeepSeekModel::DeepSeekChat
+/// Handles presses on the /settings menu: `action` is a preference to choose, a preference and its new value,
+/// or one of menu, reset and close
+async fn settings_callback(bot: Bot, query: CallbackQuery, action: String) -> ResponseResult<()> {
+    let Some(message) = query.regular_message().cloned() else {
+        retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+        return Ok(());
     };
-    let query_type = msg.result_id;
-    let query = msg.query;
-    let inline_message_id = msg.inline_message_id.unwrap_or_default();
-    let mut tips = String::new();
-    log::debug!("inline message id = {}", inline_message_id.to_owned());
-    match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), format!("{}\n\n_Asking question\\.\\.\\._", escape_markdown(query.to_owned())))
-        .parse_mode(ParseMode::MarkdownV2)
-    ) {
-        Ok(_) => {
-            let role = check_user_valid(msg.from.to_owned());
-            match role {
-                Ok(valid) => {
-                    if !valid {
-                        match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), format!("*Q: {}*\n\n_User does not have permission\\._", escape_markdown(query.to_owned())))
-                            .parse_mode(ParseMode::MarkdownV2)
-                        ) {
-                            Ok(_) => (),
-                            Err(e) => log::error!("Error updating inline hint: {}", e),
-                        }
-                        return Ok(());
-                    }
-                }
-                Err(e) => {
-                    log::error!("Error when checking role: {}", e);
-                    return Ok(());
+    let change = action.split_once(':');
+    if (change.is_some() || action == "reset" || action == "close") && !is_chat_admin(&bot, &message.chat, &query.from).await {
+        retry_future!(bot.answer_callback_query(query.id.to_owned())
+            .text("Only group administrators can change these settings.")
+            .show_alert(true))?;
+        return Ok(());
+    }
+    if action == "close" {
+        retry_future!(bot.edit_message_reply_markup(message.chat.id, message.id))?;
+        retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+        return Ok(());
+    }
+    let chat_id = message.chat.id.0.to_string();
+    let mut reply_markup = None;
+    if change.is_some() || action == "reset" {
+        let result = settings::update_chat_settings(chat_id.to_owned(), |settings| match change {
+            Some((field, value)) => {
+                if !menu::apply(&mut settings.preferences, field, value) {
+                    log::warn!("Unknown settings choice: {}", action);
                 }
             }
-            let search_driver = search::SearchDriver::from(api.to_owned());
-            let need_search = if query_type == "think" {
-                Ok(false)
-            } else {
-                retry_future!(search_driver.determine(query.to_owned()))
-            };
-            let system_prompt = match need_search {
-                Ok(need_search) => {
-                    if need_search {
-                        log::debug!("Search invoked.");
-                        let system_prompt = retry_future!(search_driver.search_and_summary(query.to_owned()));
-                        tips = String::from("> Searching invoked\\. The answer may contain information from the Internet\\.");
-                        match system_prompt {
-                            Ok(system_prompt) => system_prompt,
-                            Err(e) => {
-                                log::error!("Error when fetching system prompt: {}", e);
-                                String::new()
-                            }
-                        }
-                    } else {
-                        String::new()
-                    }
-                },
-                Err(e) => {
-                    log::error!("Error when determining need_search: {}", e);
-                    String::new()
-                }
-            };
-            if matches!(model, deepseek::types::DeepSeekModel::DeepSeekReasoner) {
-                tips = String::from("> This chat uses `deepseek-r1` model\\.")
-            }
-            match retry_future!(api.single_message_dialog_with_system(MAX_TOKEN, query.to_owned(), system_prompt.to_owned(), model.clone())) {
-                Ok(reply) => {
-                    log::debug!("received response from DeepSeek = {}", escape_markdown(reply.to_owned()));
-                    match retry_future!(bot.edit_message_text_inline(inline_message_id.to_owned(), format!("*Q: {}*\nA: {}\n{}", escape_markdown(query.to_owned()), escape_markdown(reply.to_owned()), tips))
-                        .parse_mode(ParseMode::MarkdownV2)
-                    ) {
-                        Ok(_) => {
-                            log::debug!("sent response = {}", escape_markdown(reply));
-                            match retry_future!(bot.edit_message_reply_markup_inline(inline_message_id.to_owned())
-                                .reply_markup(generate_keyboard())) {
-                                Ok(_) => (),
-                                Err(e) => log::error!("Error updating inline button: {}", e),
-                            }
-                        }
-                        Err(e) => log::error!("Error sending response: {}", e)
-                    }
+            None => settings.preferences = settings::Preferences::default(),
+        });
+        if let Err(e) = result {
+            log::error!("Cannot update chat settings: {}", e);
+        }
+    } else if action != "menu" {
+        reply_markup = menu::submenu(&action);
+    }
+    let preferences = chat_preferences(chat_id);
+    let reply_markup = reply_markup.unwrap_or_else(|| menu::main_menu(&preferences, MAX_TOKEN));
+    match retry_future!(bot.edit_message_text(message.chat.id, message.id, menu::describe(settings_title(&message.chat), &preferences, MAX_TOKEN))
+        .reply_markup(reply_markup.clone())) {
+        Ok(_) => (),
+        Err(RequestError::Api(ApiError::MessageNotModified)) => (),
+        Err(e) => log::error!("Error updating settings menu: {}", e),
+    }
+    retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+    Ok(())
+}
+
+/// Handles the buttons on answers. `action` is one of regenerate, continue, think and search, then the
+/// answer's id. Chat answers get a new reply, inline answers are replaced.
+#[allow(clippy::too_many_arguments)]
+async fn answer_callback(bot: Bot, query: CallbackQuery, me: Me, action: String, api: DeepSeekAPI, search_driver: search::SearchDriver, answers: Arc<answers::AnswerStore>, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
+    if query.regular_message().is_some_and(chat_disabled) {
+        retry_future!(bot.answer_callback_query(query.id.to_owned())
+            .text("The bot is turned off in this chat.")
+            .show_alert(true))?;
+        return Ok(());
+    }
+    let Some(context) = action.split_once(':').and_then(|(_, id)| contexts.get(id)) else {
+        retry_future!(bot.answer_callback_query(query.id.to_owned())
+            .text("This answer is too old, please ask again.")
+            .show_alert(true))?;
+        return Ok(());
+    };
+    match check_user_valid(query.from.to_owned()) {
+        Ok(true) => (),
+        Ok(false) => {
+            retry_future!(bot.answer_callback_query(query.id.to_owned())
+                .text("User doesn't have permission.")
+                .show_alert(true))?;
+            return Ok(());
+        }
+        Err(e) => {
+            log::error!("Error checking user permission: {}", e);
+            retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+            return Ok(());
+        }
+    }
+    retry_future!(bot.answer_callback_query(query.id.to_owned()).text("Working on it..."))?;
+    let action = action.split_once(':').map(|(action, _)| action).unwrap_or_default();
+    let mut question = context.question.to_owned();
+    question.asker = Asker { user: query.from.id.0, chat: query.regular_message().map(|message| message.chat.id.0) };
+    let task = tasks.start(query.from.id.0, query.regular_message().map(|message| message.chat.id.0));
+    let mut placeholder = None;
+    if let Some(inline_message_id) = query.inline_message_id.to_owned() {
+        match retry_future!(bot.edit_message_reply_markup_inline(inline_message_id.to_owned()).reply_markup(stop_keyboard(&task))) {
+            Ok(_) => (),
+            Err(e) => log::error!("Error adding stop button: {}", e),
+        }
+    } else if let Some(message) = query.regular_message() {
+        placeholder = send_placeholder(&bot, message, &task).await;
+    }
+    let api = show_queue_position(&api, &bot, placeholder.as_ref(), &task);
+    let result = match action {
+        "continue" => continue_answer(&api, &context, &task.cancel, question.asker).await,
+        _ => {
+            match action {
+                "think" => {
+                    question.model = deepseek::types::DeepSeekModel::DeepSeekReasoner;
+                    question.search_mode = SearchMode::Never;
                 }
-                Err(e) => log::error!("Unable to get response from DeepSeek: {}", e)
+                "search" => question.search_mode = SearchMode::Always,
+                _ => (),
             }
+            answer_query(&api, &search_driver, &question, &task.cancel).await
+        }
+    };
+    drop(task);
+    let answer = match result {
+        Ok(answer) => answer,
+        Err(e) => {
+            log::error!("Unable to get response from DeepSeek: {}", e);
+            delete_placeholder(&bot, placeholder).await;
+            if let Some(message) = query.regular_message() {
+                retry_future!(reply_to_message(bot.to_owned(), message.to_owned(), String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.")))?;
+            }
+            return Ok(());
+        }
+    };
+    // further continuations build on the whole answer so far
+    let full_answer = if action == "continue" {
+        Answer {
+            reply: format!("{}{}", context.answer.reply, answer.reply),
+            reasoning: context.answer.reasoning.to_owned(),
+            ..answer.to_owned()
+        }
+    } else {
+        answer.to_owned()
+    };
+    let text = full_answer.markdown();
+    let new_context = AnswerContext { question: question.to_owned(), answer: full_answer };
+    if let Some(inline_message_id) = query.inline_message_id.to_owned() {
+        let actions = answer_actions(&contexts, &inline_message_id, new_context);
+        if let Err(e) = show_inline_answer(&bot, &me, &answers, inline_message_id, &question.query, &text, actions).await {
+            log::error!("Error sending response: {}", e);
+        }
+    } else if let Some(message) = query.regular_message() {
+        let actions = answer_actions(&contexts, &question.query, new_context);
+        match retry_future!(reply_answer_with_keyboard(bot.to_owned(), message.to_owned(), answer.markdown(), Some(InlineKeyboardMarkup::new(actions.clone())))) {
+            Ok(msg) => log::debug!("sent response = {}", msg),
+            Err(e) => log::error!("Error sending response: {}", e),
         }
-        Err(e) => log::error!("Error when updating inline hint: {}", e)
     }
+    delete_placeholder(&bot, placeholder).await;
     Ok(())
 }
 
-async fn chat_handler(bot: Bot, msg: Message, api: DeepSeekAPI) -> ResponseResult<()> {
-    log::debug!("called chat_handler");
-    if msg.via_bot != None {
-        return Ok(())
+/// Sends every superuser a request from `user` to use the bot, along with their `message`
+async fn send_access_request(bot: &Bot, user: &User, message: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (text, keyboard) = access::request_card(user, message);
+    let mut sent = false;
+    for superuser in config::get_config()?.superusers() {
+        let chat_id = match superuser.parse::<i64>() {
+            Ok(chat_id) => ChatId(chat_id),
+            Err(e) => {
+                log::error!("Invalid superuser uid {}: {}", superuser, e);
+                continue;
+            }
+        };
+        match retry_future!(bot.send_message(chat_id, text.to_owned()).reply_markup(keyboard.clone())) {
+            Ok(_) => sent = true,
+            Err(e) => log::error!("Error sending access request to {}: {}", superuser, e),
+        }
     }
-    if let Some(user) = msg.to_owned().from {
-        match check_user_valid(user) {
-            Ok(valid) => {
-                if !valid {
-                    match retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("User doesn't have permission."))) {
-                        Ok(_) => (),
-                        Err(e) => log::error!("Error sending permission information: {}", e),
-                    }
-                    return Ok(());
-                }
+    if !sent {
+        return Err("no superuser could be reached".into());
+    }
+    access::add_access_request(user.id.0.to_string())?;
+    Ok(())
+}
+
+async fn request_access_command(bot: Bot, msg: Message, message: String) -> ResponseResult<()> {
+    let Some(user) = msg.from.to_owned() else {
+        return Ok(());
+    };
+    let request = match access::get_access_request(user.id.0.to_string()) {
+        Ok(request) => request,
+        Err(e) => {
+            log::error!("Error reading access requests: {}", e);
+            return Ok(());
+        }
+    };
+    let reply = match check_user_valid(user.to_owned()) {
+        Ok(true) => "You already have access to the bot.",
+        Ok(false) if request.as_ref().is_some_and(|request| access::is_recent(request, deepseek::page::now())) => {
+            match request.map(|request| request.status) {
+                Some(access::RequestStatus::Pending) => "Your request is still waiting for a decision.",
+                _ => "You have asked recently, please try again tomorrow.",
             }
+        }
+        Ok(false) => match send_access_request(&bot, &user, &message).await {
+            Ok(()) => "Your request has been sent. You will be told when it is decided.",
             Err(e) => {
-                log::error!("Error checking user permission: {}", e);
-                return Ok(());
+                log::error!("Error sending access request: {}", e);
+                "Your request could not be sent, please try again later."
             }
+        },
+        Err(e) => {
+            log::error!("Error checking user permission: {}", e);
+            return Ok(());
+        }
+    };
+    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from(reply)))?;
+    Ok(())
+}
+
+/// Handles the buttons on access requests. `action` is approve, temporary or deny, then the user's id.
+async fn access_callback(bot: Bot, query: CallbackQuery, action: String) -> ResponseResult<()> {
+    if !matches!(user::check_uid(query.from.id.0.to_string()), Ok(user::Role::SuperUser)) {
+        retry_future!(bot.answer_callback_query(query.id.to_owned())
+            .text("Only superusers can decide on access requests.")
+            .show_alert(true))?;
+        return Ok(());
+    }
+    let Some((decision, uid)) = action.split_once(':') else {
+        retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+        return Ok(());
+    };
+    let (result, status, outcome, notice) = match decision {
+        "approve" => (
+            config::add_trusted_user(uid.to_string()),
+            access::RequestStatus::Approved,
+            String::from("Approved"),
+            String::from("Your request was approved, you can now use the bot."),
+        ),
+        "temporary" => match config::add_trusted_user_until(uid.to_string(), Some(deepseek::page::now() + access::TEMPORARY_ACCESS)) {
+            // the user was already trusted for good, which is kept
+            Ok(None) => (
+                Ok(()),
+                access::RequestStatus::Approved,
+                String::from("Already approved for good"),
+                String::from("Your request was approved, you can now use the bot."),
+            ),
+            result => (
+                result.map(|_| ()),
+                access::RequestStatus::Approved,
+                String::from("Approved for 7 days"),
+                String::from("Your request was approved, you can use the bot for the next 7 days."),
+            ),
+        },
+        // only the request is turned down, whoever is trusted already stays so
+        "deny" => (
+            Ok(()),
+            access::RequestStatus::Denied,
+            String::from("Denied"),
+            String::from("Your request to use the bot was denied."),
+        ),
+        _ => {
+            retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
+            return Ok(());
         }
+    };
+    if let Err(e) = result.and_then(|_| access::set_access_request_status(uid.to_string(), status)) {
+        log::error!("Cannot update permission: {}", e);
+        retry_future!(bot.answer_callback_query(query.id.to_owned()).text("Permission could not be updated."))?;
+        return Ok(());
+    }
+    retry_future!(bot.answer_callback_query(query.id.to_owned()).text(outcome.to_owned()))?;
+    if let Some(message) = query.regular_message() {
+        // replaces the buttons with the decision
+        let text = format!("{}\n\n{} by {}.", message.text().unwrap_or_default(), outcome, query.from.full_name());
+        match retry_future!(bot.edit_message_text(message.chat.id, message.id, text.to_owned())) {
+            Ok(_) => (),
+            Err(e) => log::error!("Error updating access request: {}", e),
+        }
+    }
+    match uid.parse::<i64>() {
+        Ok(chat_id) => match retry_future!(bot.send_message(ChatId(chat_id), notice.to_owned())) {
+            Ok(_) => (),
+            Err(e) => log::error!("Error notifying user {} of the decision: {}", uid, e),
+        },
+        Err(e) => log::error!("Invalid user id {}: {}", uid, e),
+    }
+    Ok(())
+}
+
+/// Handles the Stop button. Requests can be stopped by whoever asked, or by a superuser.
+async fn stop_callback(bot: Bot, query: CallbackQuery, id: String, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
+    let superuser = match user::check_uid(query.from.id.0.to_string()) {
+        Ok(role) => matches!(role, user::Role::SuperUser),
+        Err(e) => {
+            log::error!("Error checking user permission: {}", e);
+            false
+        }
+    };
+    let text = if tasks.cancel(&id, query.from.id.0, superuser) {
+        "Stopping..."
     } else {
-        return Ok(())
+        "This answer has already finished, or it isn't yours to stop."
+    };
+    retry_future!(bot.answer_callback_query(query.id.to_owned()).text(text))?;
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn callback_handler(bot: Bot, query: CallbackQuery, me: Me, api: DeepSeekAPI, search_driver: search::SearchDriver, answers: Arc<answers::AnswerStore>, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
+    log::debug!("called callback_handler");
+    let data = query.data.to_owned().unwrap_or_default();
+    if let Some(action) = data.strip_prefix(menu::CALLBACK_PREFIX) {
+        settings_callback(bot, query.to_owned(), action.to_string()).await?;
+    } else if let Some(action) = data.strip_prefix(ANSWER_CALLBACK_PREFIX) {
+        answer_callback(bot, query.to_owned(), me, action.to_string(), api, search_driver, answers, contexts, tasks).await?;
+    } else if let Some(action) = data.strip_prefix(access::CALLBACK_PREFIX) {
+        access_callback(bot, query.to_owned(), action.to_string()).await?;
+    } else if let Some(id) = data.strip_prefix(STOP_CALLBACK_PREFIX) {
+        stop_callback(bot, query.to_owned(), id.to_string(), tasks).await?;
+    } else {
+        retry_future!(bot.answer_callback_query(query.id.to_owned()))?;
     }
-    match msg.text() {
-        Some(text) => {
-            log::debug!("Received msg = {}", text);
-            let mut response = String::from("You are seeing this message because there was an error when we communicate with DeepSeek. Check the log for details.");
-            match retry_future!(api.single_message_dialog(MAX_TOKEN, String::from(text), deepseek::types::DeepSeekModel::DeepSeekChat)) {
-                Ok(reply) => {
-                    log::debug!("received response from DeepSeek = {}", escape_markdown(reply.to_owned()));
-                    response = reply;
-                },
-                Err(e) => {
-                    log::error!("Unable to get response from DeepSeek: {}", e);
-                },
+    Ok(())
+}
+
+async fn system_command(bot: Bot, msg: Message, args: String) -> ResponseResult<()> {
+    if !ensure_chat_admin(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let chat_id = msg.chat.id.0.to_string();
+    let args = args.trim().to_string();
+    if args.is_empty() {
+        let reply = match settings::get_chat_settings(chat_id.to_owned()) {
+            Ok(settings) => match (settings.system_prompt, settings.persona) {
+                (Some(system_prompt), _) => format!("The system prompt of this chat is:\n{}", system_prompt),
+                (None, Some(persona)) => format!("This chat uses the persona {}:\n{}", persona, chat_system_prompt(chat_id)),
+                (None, None) => String::from("This chat has no system prompt. Set one with /system <prompt>."),
+            },
+            Err(e) => {
+                log::error!("Error reading chat settings: {}", e);
+                return Ok(());
+            }
+        };
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+        return Ok(());
+    }
+    let clear = args.eq_ignore_ascii_case("clear");
+    match settings::update_chat_settings(chat_id, |settings| {
+        settings.system_prompt = if clear { None } else { Some(args.to_owned()) };
+        settings.persona = None;
+    }) {
+        Ok(()) => {
+            let reply = if clear { "The system prompt of this chat is cleared." } else { "The system prompt of this chat is set." };
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from(reply)))?;
+        }
+        Err(e) => log::error!("Cannot update chat settings: {}", e),
+    }
+    Ok(())
+}
+
+async fn persona_command(bot: Bot, msg: Message, args: String) -> ResponseResult<()> {
+    let personas = match config::get_config() {
+        Ok(config) => config.personas,
+        Err(e) => {
+            log::error!("Error reading config: {}", e);
+            return Ok(());
+        }
+    };
+    let chat_id = msg.chat.id.0.to_string();
+    let args = args.trim();
+    if args.is_empty() {
+        let reply = if personas.is_empty() {
+            String::from("No personas are configured.")
+        } else {
+            let current = settings::get_chat_settings(chat_id).ok().and_then(|settings| settings.persona);
+            let mut reply = String::from("Personas:");
+            for name in personas.keys() {
+                let marker = if current.as_ref() == Some(name) { " (current)" } else { "" };
+                reply.push_str(&format!("\n- {}{}", name, marker));
             }
-            match retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), response.to_owned())) {
-                Ok(msg) => log::debug!("sent response = {}", msg),
-                Err(e) => log::error!("Error sending response: {}", e),
+            reply.push_str("\n\nSwitch with /persona <name>, or stop with /persona none.");
+            reply
+        };
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+        return Ok(());
+    }
+    let persona = if args.eq_ignore_ascii_case("none") {
+        None
+    } else {
+        match personas.keys().find(|name| name.eq_ignore_ascii_case(args)) {
+            Some(name) => Some(name.to_owned()),
+            None => {
+                retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Unknown persona. Send /persona to list them.")))?;
+                return Ok(());
             }
-        },
-        None => (),
+        }
+    };
+    if !ensure_chat_admin(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let reply = match &persona {
+        Some(name) => format!("This chat now uses the persona {}.", name),
+        None => String::from("This chat no longer uses a persona."),
+    };
+    match settings::update_chat_settings(chat_id, |settings| {
+        settings.persona = persona;
+        settings.system_prompt = None;
+    }) {
+        Ok(()) => {
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+        }
+        Err(e) => log::error!("Cannot update chat settings: {}", e),
+    }
+    Ok(())
+}
+
+/// The usage recorded this month, and the prices to estimate its cost with
+fn usage_this_month(now: u64) -> Result<(Vec<usage::UsageRecord>, usage::PricingConfig), Box<dyn std::error::Error + Send + Sync>> {
+    let pricing = config::get_config()?.pricing;
+    Ok((usage::records_since(usage::month_start(now))?, pricing))
+}
+
+async fn usage_command(bot: Bot, msg: Message) -> ResponseResult<()> {
+    if !ensure_trusted(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    let now = deepseek::page::now();
+    let (records, pricing) = match usage_this_month(now) {
+        Ok(usage) => usage,
+        Err(e) => {
+            log::error!("Error reading usage: {}", e);
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Usage could not be read.")))?;
+            return Ok(());
+        }
+    };
+    let user = sender_id(&msg);
+    let mine = records.iter().filter(|record| record.user == user).collect::<Vec<_>>();
+    let today = usage::totals(mine.iter().copied().filter(|record| record.time >= usage::day_start(now)), &pricing);
+    let month = usage::totals(mine.iter().copied(), &pricing);
+    let reply = format!("Today: {}\nThis month: {}\n\nCosts are estimated from DeepSeek's prices, in USD.", today, month);
+    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+    Ok(())
+}
+
+async fn stats_command(bot: Bot, msg: Message, args: String, cache: Option<&deepseek::cache::SearchCache>) -> ResponseResult<()> {
+    let Some(user) = msg.from.to_owned() else {
+        return Ok(());
+    };
+    if !matches!(user::check_uid(user.id.0.to_string()), Ok(user::Role::SuperUser)) {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("You are not a superuser.")))?;
+        return Ok(());
+    }
+    let now = deepseek::page::now();
+    let (records, pricing) = match usage_this_month(now) {
+        Ok(usage) => usage,
+        Err(e) => {
+            log::error!("Error reading usage: {}", e);
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Usage could not be read.")))?;
+            return Ok(());
+        }
     };
+    if records.is_empty() {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("Nothing has been asked this month.")))?;
+        return Ok(());
+    }
+    let mut reply = format!("Usage this month\n\n{}\nBy API key:\n{}", usage::report(&records, &pricing), keys::describe());
+    if let Some(cache) = cache {
+        reply.push_str(&format!("\n\nSearch cache since startup:\n{}", cache.stats));
+    }
+    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+    if args.trim() == "chart" {
+        match usage::daily_cost_chart(&records, &pricing, usage::month_start(now), now) {
+            Ok(png) => {
+                match retry_future!(bot.send_photo(msg.chat.id, InputFile::memory(png.clone()).file_name("stats.png"))
+                    .caption("Daily cost this month")
+                    .reply_parameters(ReplyParameters::new(msg.id))
+                ) {
+                    Ok(_) => (),
+                    Err(e) => log::error!("Error sending chart: {}", e),
+                }
+            }
+            Err(e) => log::error!("Error drawing chart: {}", e),
+        }
+    }
+    Ok(())
+}
 
+async fn set_chat_enabled(bot: Bot, msg: Message, enabled: bool) -> ResponseResult<()> {
+    if !is_group(&msg) {
+        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("This command only works in groups.")))?;
+        return Ok(());
+    }
+    if !ensure_chat_admin(bot.to_owned(), msg.to_owned()).await {
+        return Ok(());
+    }
+    match settings::update_chat_settings(msg.chat.id.0.to_string(), |settings| settings.enabled = enabled) {
+        Ok(()) => {
+            let reply = if enabled { "The bot is now enabled in this group." } else { "The bot is now disabled in this group." };
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from(reply)))?;
+        }
+        Err(e) => log::error!("Cannot update chat settings: {}", e),
+    }
     Ok(())
 }
 
-async fn command_handler(bot: Bot, msg: Message, cmd: Command, api: DeepSeekAPI) -> ResponseResult<()> {
+#[allow(clippy::too_many_arguments)]
+async fn command_handler(bot: Bot, msg: Message, cmd: Command, api: DeepSeekAPI, search_driver: search::SearchDriver, answers: Arc<answers::AnswerStore>, index: Arc<index::DocumentIndex>, contexts: Arc<answers::AnswerStore<AnswerContext>>, tasks: Arc<tasks::TaskRegistry>) -> ResponseResult<()> {
     match cmd {
+        Command::Start(payload) => {
+            match payload.strip_prefix(FULL_ANSWER_PREFIX) {
+                Some(id) => match answers.get(id) {
+                    Some(answer) => {
+                        retry_future!(reply_answer_to_message(bot.to_owned(), msg.to_owned(), answer.to_owned()))?;
+                    }
+                    None => {
+                        retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), String::from("This answer is no longer available.")))?;
+                    }
+                },
+                None if payload == access::START_PARAMETER => {
+                    request_access_command(bot, msg, String::new()).await?;
+                }
+                None => {
+                    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), Command::descriptions().to_string()))?;
+                }
+            }
+        }
         Command::Help => {
             retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), Command::descriptions().to_string()))?;
         }
@@ -284,14 +2020,84 @@ async fn command_handler(bot: Bot, msg: Message, cmd: Command, api: DeepSeekAPI)
             retry_future!(bot.send_dice(msg.chat.id))?;
         }
         Command::Info => {
-            match retry_future!(api.get_balance()) {
-                Ok(reply) => {
-                    retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
-                }
-                Err(e) => {
-                    log::error!("Error when fetching balance information: {}", e);
+            // the balance of the keys this chat's requests are paid from
+            let mut reply = String::new();
+            for key in keys::serving(sender_id(&msg), Some(msg.chat.id.0)) {
+                let key_api = DeepSeekAPI { token: key.token, ..api.clone() };
+                match retry_future!(key_api.get_balance()) {
+                    Ok(balance) => reply.push_str(&format!("Key {}:\n{}", key.name, balance)),
+                    Err(e) => {
+                        log::error!("Error when fetching balance information of key {}: {}", key.name, e);
+                        reply.push_str(&format!("Key {}: the balance could not be fetched.\n\n", key.name));
+                    }
                 }
             }
+            if reply.is_empty() {
+                reply.push_str("No API key is left to answer in this chat.\n\n");
+            }
+            if keys::restricted(sender_id(&msg), Some(msg.chat.id.0)) {
+                reply.push_str("The balance is low, deepseek-reasoner is disabled.\n\n");
+            }
+            if let Some(cache) = &search_driver.cache {
+                reply.push_str(&format!("Search cache:\n{}", cache.stats));
+            }
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
+        }
+        Command::Ask(args) => {
+            ask_command(bot, msg, args, api, search_driver, contexts, tasks, deepseek::types::DeepSeekModel::DeepSeekChat, SearchMode::Auto).await?;
+        }
+        Command::Search(args) => {
+            ask_command(bot, msg, args, api, search_driver, contexts, tasks, deepseek::types::DeepSeekModel::DeepSeekChat, SearchMode::Always).await?;
+        }
+        Command::NoSearch(args) => {
+            ask_command(bot, msg, args, api, search_driver, contexts, tasks, deepseek::types::DeepSeekModel::DeepSeekChat, SearchMode::Never).await?;
+        }
+        Command::Think(args) => {
+            ask_command(bot, msg, args, api, search_driver, contexts, tasks, deepseek::types::DeepSeekModel::DeepSeekReasoner, SearchMode::Never).await?;
+        }
+        Command::Enable => {
+            set_chat_enabled(bot, msg, true).await?;
+        }
+        Command::Disable => {
+            set_chat_enabled(bot, msg, false).await?;
+        }
+        Command::Docs => {
+            list_documents(bot, msg, index).await?;
+        }
+        Command::AskDocs(args) => {
+            ask_documents_command(bot, msg, args, api, index).await?;
+        }
+        Command::ForgetDoc(args) => {
+            forget_document(bot, msg, args, index).await?;
+        }
+        Command::Tldr(args) => {
+            tldr_command(bot, msg, args, api, search_driver).await?;
+        }
+        Command::System(args) => {
+            system_command(bot, msg, args).await?;
+        }
+        Command::Persona(args) => {
+            persona_command(bot, msg, args).await?;
+        }
+        Command::Settings => {
+            settings_command(bot, msg).await?;
+        }
+        Command::Usage => {
+            usage_command(bot, msg).await?;
+        }
+        Command::Stats(args) => {
+            stats_command(bot, msg, args, search_driver.cache.as_deref()).await?;
+        }
+        Command::Request(message) => {
+            request_access_command(bot, msg, message).await?;
+        }
+        Command::Cancel => {
+            let user = sender_id(&msg);
+            let reply = match tasks.cancel_all(msg.chat.id.0, user) {
+                0 => String::from("You have no questions being answered in this chat."),
+                count => format!("Stopped {} request(s).", count),
+            };
+            retry_future!(reply_to_message(bot.to_owned(), msg.to_owned(), reply.to_owned()))?;
         }
         Command::Grant => {
             if let Some(user) = msg.to_owned().from {
@@ -347,30 +2153,119 @@ async fn serve() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 
     let bot = Bot::new(config.telegram_bot_token);
     let deepseek_api_token = config.deepseek_api_token;
+    let mut spent = std::collections::HashMap::<String, f64>::new();
+    for record in usage::records_since(usage::month_start(deepseek::page::now()))? {
+        *spent.entry(record.key.to_owned()).or_default() += config.pricing.cost(&record);
+    }
+    keys::init(deepseek_api_token.clone(), config.api_keys, config.key_selection, spent);
+    let limiter = Arc::new(deepseek::limiter::RequestLimiter::new(config.limiter));
+    let search_cache = config.cache.build();
+    if let Some(search_cache) = &search_cache {
+        tokio::spawn(deepseek::cache::save_periodically(search_cache.clone(), config.cache.save_interval));
+    }
+    let search_driver = search::SearchDriver::new(
+        DeepSeekAPI { token: deepseek_api_token.clone(), timeout: TIMEOUT, client: client.clone(), limiter: Some(limiter.clone()), requester: Default::default(), on_usage: None },
+        config.search.backend.build(client.clone(), search_cache.clone()),
+        config.search.params,
+        config.summary,
+        search_cache,
+    );
+    let answers = Arc::new(answers::AnswerStore::default());
+    let index = Arc::new(index::DocumentIndex::load());
+    let contexts = Arc::new(answers::AnswerStore::<AnswerContext>::default());
+    let tasks = Arc::new(tasks::TaskRegistry::default());
+    let previews = Arc::new(previews::PreviewCache::default());
+    tokio::spawn(async {
+        loop {
+            if let Err(e) = config::prune_trusted_users() {
+                log::error!("Error removing expired users: {}", e);
+            }
+            tokio::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+        }
+    });
+    tokio::spawn(balance::watch(bot.clone(), DeepSeekAPI { token: deepseek_api_token.clone(), timeout: TIMEOUT, client: client.clone(), limiter: None, requester: Default::default(), on_usage: None }));
     Dispatcher::builder(bot, dptree::entry()
         .branch(
             {
                 let deepseek_api_token = deepseek_api_token.clone();
                 let client = client.clone();
-                Update::filter_chosen_inline_result().endpoint(move |bot: Bot, msg: ChosenInlineResult | {
+                let limiter = limiter.clone();
+                let search_driver = search_driver.clone();
+                let answers = answers.clone();
+                let contexts = contexts.clone();
+                let tasks = tasks.clone();
+                Update::filter_chosen_inline_result().endpoint(move |bot: Bot, msg: ChosenInlineResult, me: Me| {
+                    let deepseek_api_token = deepseek_api_token.clone();
+                    let client = client.clone();
+                    let limiter = limiter.clone();
+                    let search_driver = search_driver.clone();
+                    let answers = answers.clone();
+                    let contexts = contexts.clone();
+                    let tasks = tasks.clone();
+                    async move {
+                        inline_result_handler(bot, msg, me, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client, limiter: Some(limiter), requester: Default::default(), on_usage: None }, search_driver, answers, contexts, tasks).await
+                    }
+                })
+            }
+        ).branch(
+            {
+                let deepseek_api_token = deepseek_api_token.clone();
+                let client = client.clone();
+                let limiter = limiter.clone();
+                let previews = previews.clone();
+                Update::filter_inline_query().endpoint(move |bot: Bot, msg: InlineQuery| {
                     let deepseek_api_token = deepseek_api_token.clone();
                     let client = client.clone();
+                    let limiter = limiter.clone();
+                    let previews = previews.clone();
                     async move {
-                        inline_result_handler(bot, msg, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client }).await
+                        inline_handler(bot, msg, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client, limiter: Some(limiter), requester: Default::default(), on_usage: None }, previews).await
                     }
                 })
             }
         ).branch(
-            Update::filter_inline_query().endpoint(inline_handler),
+            {
+                let deepseek_api_token = deepseek_api_token.clone();
+                let client = client.clone();
+                let limiter = limiter.clone();
+                let search_driver = search_driver.clone();
+                let answers = answers.clone();
+                let contexts = contexts.clone();
+                let tasks = tasks.clone();
+                Update::filter_callback_query().endpoint(move |bot: Bot, query: CallbackQuery, me: Me| {
+                    let deepseek_api_token = deepseek_api_token.clone();
+                    let client = client.clone();
+                    let limiter = limiter.clone();
+                    let search_driver = search_driver.clone();
+                    let answers = answers.clone();
+                    let contexts = contexts.clone();
+                    let tasks = tasks.clone();
+                    async move {
+                        callback_handler(bot, query, me, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client, limiter: Some(limiter), requester: Default::default(), on_usage: None }, search_driver, answers, contexts, tasks).await
+                    }
+                })
+            }
         ).branch(
             {
                 let deepseek_api_token = deepseek_api_token.clone();
                 let client = client.clone();
+                let limiter = limiter.clone();
+                let search_driver = search_driver.clone();
+                let answers = answers.clone();
+                let index = index.clone();
+                let contexts = contexts.clone();
+                let tasks = tasks.clone();
                 Update::filter_message().filter_command::<Command>().endpoint(move |bot: Bot, msg: Message, cmd: Command| {
                     let deepseek_api_token = deepseek_api_token.clone();
                     let client = client.clone();
+                    let limiter = limiter.clone();
+                    let search_driver = search_driver.clone();
+                    let answers = answers.clone();
+                    let index = index.clone();
+                    let contexts = contexts.clone();
+                    let tasks = tasks.clone();
                     async move {
-                        command_handler(bot, msg, cmd, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client: client }).await
+                        command_handler(bot, msg, cmd, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client, limiter: Some(limiter), requester: Default::default(), on_usage: None }, search_driver, answers, index, contexts, tasks).await
                     }
                 })
             }
@@ -378,16 +2273,33 @@ async fn serve() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         {
                 let deepseek_api_token = deepseek_api_token.clone();
                 let client = client.clone();
-                Update::filter_message().endpoint(move |bot: Bot, msg: Message| {
+                let limiter = limiter.clone();
+                let search_driver = search_driver.clone();
+                let index = index.clone();
+                let contexts = contexts.clone();
+                let tasks = tasks.clone();
+                Update::filter_message().endpoint(move |bot: Bot, msg: Message, me: Me| {
                     let deepseek_api_token = deepseek_api_token.clone();
                     let client = client.clone();
+                    let limiter = limiter.clone();
+                    let search_driver = search_driver.clone();
+                    let index = index.clone();
+                    let contexts = contexts.clone();
+                    let tasks = tasks.clone();
                     async move {
-                        chat_handler(bot, msg, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client: client }).await
+                        chat_handler(bot, msg, me, DeepSeekAPI { token: deepseek_api_token, timeout: TIMEOUT, client, limiter: Some(limiter), requester: Default::default(), on_usage: None }, search_driver, index, contexts, tasks).await
                     }
                 })
             }
         )
     ).enable_ctrlc_handler().build().dispatch().await;
+    if let Some(search_cache) = search_driver.cache.to_owned() {
+        match tokio::task::spawn_blocking(move || search_cache.save()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => log::error!("Error saving search cache: {}", e),
+            Err(e) => log::error!("Error saving search cache: {}", e),
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,667 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+
+/// ref:
+/// https://core.telegram.org/bots/api#markdownv2-style
+const ESCAPE_CHARS: &str = r"\_*[]()~`>#+-=|{}.!";
+
+/// Escapes text outside of any entity.
+pub fn escape(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        if ESCAPE_CHARS.contains(c) {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+/// Escapes text inside `pre` and `code` entities.
+fn escape_code(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('`', "\\`")
+}
+
+/// Escapes the `(...)` part of an inline link.
+fn escape_link_url(url: &str) -> String {
+    url.replace('\\', "\\\\").replace(')', "\\)")
+}
+
+/// Escapes text so that CommonMark renders it literally, for composing Markdown around model output.
+pub fn escape_commonmark(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_ascii_punctuation() {
+            ret.push('\\');
+        }
+        ret.push(c);
+    }
+    ret
+}
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Gap {
+    Line,
+    Paragraph,
+}
+
+/// Table cells are collected as plain text and laid out in a code block, since Telegram has no tables.
+#[derive(Default)]
+struct Table {
+    rows: Vec<Vec<String>>,
+    cell: String,
+}
+
+impl Table {
+    fn render(&self) -> String {
+        let columns = self.rows.iter().map(|row| row.len()).max().unwrap_or_default();
+        let widths = (0..columns)
+            .map(|column| self.rows.iter().filter_map(|row| row.get(column)).map(|cell| cell.chars().count()).max().unwrap_or_default())
+            .collect::<Vec<_>>();
+        let mut ret = String::new();
+        for (index, row) in self.rows.iter().enumerate() {
+            let cells = widths.iter()
+                .enumerate()
+                .map(|(column, width)| {
+                    let cell = row.get(column).map(String::as_str).unwrap_or_default();
+                    format!("{}{}", cell, " ".repeat(width - cell.chars().count()))
+                })
+                .collect::<Vec<_>>();
+            ret.push_str(cells.join(" | ").trim_end());
+            ret.push('\n');
+            if index == 0 {
+                ret.push_str(&widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<_>>().join("-+-"));
+                ret.push('\n');
+            }
+        }
+        ret
+    }
+}
+
+#[derive(Default)]
+struct Renderer {
+    out: String,
+    gap: Option<Gap>,
+    line_start: bool,
+    quote_depth: usize,
+    /// `quote_depth` when the last line was started
+    line_quote_depth: usize,
+    lists: Vec<Option<u64>>,
+    /// set right after a list bullet, so the item's first paragraph stays on the bullet's line
+    item_start: bool,
+    bold: usize,
+    italic: usize,
+    strike: usize,
+    code_block: bool,
+    links: Vec<Option<String>>,
+    table: Option<Table>,
+}
+
+impl Renderer {
+    fn request_gap(&mut self, gap: Gap) {
+        if self.item_start {
+            return;
+        }
+        if self.gap.is_none_or(|pending| pending < gap) {
+            self.gap = Some(gap);
+        }
+    }
+    /// Writes pending block separators and the blockquote prefix before new output
+    fn begin_output(&mut self) {
+        if let Some(gap) = self.gap.take() {
+            if !self.out.is_empty() {
+                self.out.push('\n');
+                if gap == Gap::Paragraph && (self.quote_depth == 0 || self.line_quote_depth == 0) {
+                    self.out.push('\n');
+                }
+                self.line_start = true;
+            }
+        }
+        if self.line_start || self.out.is_empty() {
+            if self.quote_depth > 0 {
+                self.out.push('>');
+            }
+            self.line_quote_depth = self.quote_depth;
+            self.line_start = false;
+        }
+        self.item_start = false;
+    }
+    fn write(&mut self, text: &str) {
+        if let Some(table) = &mut self.table {
+            table.cell.push_str(text);
+            return;
+        }
+        self.begin_output();
+        self.out.push_str(text);
+    }
+    fn text(&mut self, text: &str) {
+        if self.table.is_some() {
+            self.write(text);
+        } else if self.code_block {
+            self.write(&escape_code(text));
+        } else {
+            self.write(&escape(text));
+        }
+    }
+    fn line_break(&mut self) {
+        if let Some(table) = &mut self.table {
+            table.cell.push(' ');
+            return;
+        }
+        self.out.push('\n');
+        self.line_start = true;
+    }
+    /// Emits `marker` when entering or leaving the outermost level of a style, as MarkdownV2 can't nest one style in itself
+    fn toggle(&mut self, marker: &str, depth: usize) {
+        if depth == 0 && self.table.is_none() {
+            self.write(marker);
+        }
+    }
+    fn start(&mut self, tag: Tag) {
+        match tag {
+            Tag::Paragraph | Tag::HtmlBlock | Tag::FootnoteDefinition(_) | Tag::DefinitionList => self.request_gap(Gap::Paragraph),
+            Tag::DefinitionListTitle | Tag::DefinitionListDefinition => self.request_gap(Gap::Line),
+            Tag::Heading { .. } => {
+                self.request_gap(Gap::Paragraph);
+                self.toggle("*", self.bold);
+                self.bold += 1;
+            }
+            Tag::BlockQuote(_) => {
+                self.request_gap(Gap::Paragraph);
+                self.quote_depth += 1;
+            }
+            Tag::CodeBlock(kind) => {
+                self.request_gap(Gap::Paragraph);
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .chars()
+                        .filter(|c| c.is_ascii_alphanumeric() || "+-#_.".contains(*c))
+                        .collect::<String>(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                self.write(&format!("```{language}\n"));
+                self.code_block = true;
+            }
+            Tag::List(first) => {
+                self.request_gap(if self.lists.is_empty() { Gap::Paragraph } else { Gap::Line });
+                self.item_start = false;
+                self.lists.push(first);
+            }
+            Tag::Item => {
+                self.item_start = false;
+                self.request_gap(Gap::Line);
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let bullet = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => String::from("• "),
+                };
+                self.write(&format!("{}{}", indent, escape(&bullet)));
+                self.item_start = true;
+            }
+            Tag::Table(_) => {
+                self.request_gap(Gap::Paragraph);
+                self.table = Some(Table::default());
+            }
+            Tag::TableHead | Tag::TableRow => {
+                if let Some(table) = &mut self.table {
+                    table.rows.push(Vec::new());
+                }
+            }
+            Tag::TableCell => (),
+            Tag::Emphasis => {
+                self.toggle("_", self.italic);
+                self.italic += 1;
+            }
+            Tag::Strong => {
+                self.toggle("*", self.bold);
+                self.bold += 1;
+            }
+            Tag::Strikethrough => {
+                self.toggle("~", self.strike);
+                self.strike += 1;
+            }
+            Tag::Link { dest_url, .. } | Tag::Image { dest_url, .. } => {
+                let linkable = ["http://", "https://", "tg://", "mailto:"].iter().any(|scheme| dest_url.starts_with(scheme));
+                if linkable && self.table.is_none() && self.links.iter().all(Option::is_none) {
+                    self.write("[");
+                    self.links.push(Some(dest_url.to_string()));
+                } else {
+                    self.links.push(None);
+                }
+            }
+            Tag::MetadataBlock(_) => (),
+        }
+    }
+    fn end(&mut self, tag: TagEnd) {
+        match tag {
+            TagEnd::Paragraph | TagEnd::HtmlBlock | TagEnd::FootnoteDefinition | TagEnd::DefinitionList => self.request_gap(Gap::Paragraph),
+            TagEnd::DefinitionListTitle | TagEnd::DefinitionListDefinition => self.request_gap(Gap::Line),
+            TagEnd::Heading(_) => {
+                self.bold -= 1;
+                self.toggle("*", self.bold);
+                self.request_gap(Gap::Paragraph);
+            }
+            TagEnd::BlockQuote(_) => {
+                self.quote_depth -= 1;
+                self.request_gap(Gap::Paragraph);
+            }
+            TagEnd::CodeBlock => {
+                if !self.out.ends_with('\n') {
+                    self.out.push('\n');
+                }
+                self.out.push_str("```");
+                self.code_block = false;
+                self.request_gap(Gap::Paragraph);
+            }
+            TagEnd::List(_) => {
+                self.lists.pop();
+                self.request_gap(if self.lists.is_empty() { Gap::Paragraph } else { Gap::Line });
+            }
+            TagEnd::Item => self.request_gap(Gap::Line),
+            TagEnd::Table => {
+                if let Some(table) = self.table.take() {
+                    self.write(&format!("```\n{}```", escape_code(&table.render())));
+                }
+                self.request_gap(Gap::Paragraph);
+            }
+            TagEnd::TableHead | TagEnd::TableRow => (),
+            TagEnd::TableCell => {
+                if let Some(table) = &mut self.table {
+                    let cell = std::mem::take(&mut table.cell).trim().to_string();
+                    if let Some(row) = table.rows.last_mut() {
+                        row.push(cell);
+                    }
+                }
+            }
+            TagEnd::Emphasis => {
+                self.italic -= 1;
+                self.toggle("_", self.italic);
+            }
+            TagEnd::Strong => {
+                self.bold -= 1;
+                self.toggle("*", self.bold);
+            }
+            TagEnd::Strikethrough => {
+                self.strike -= 1;
+                self.toggle("~", self.strike);
+            }
+            TagEnd::Link | TagEnd::Image => {
+                if let Some(Some(url)) = self.links.pop() {
+                    self.write(&format!("]({})", escape_link_url(&url)));
+                }
+            }
+            TagEnd::MetadataBlock(_) => (),
+        }
+    }
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Start(tag) => self.start(tag),
+            Event::End(tag) => self.end(tag),
+            Event::Text(text) => self.text(&text),
+            Event::Code(code) => {
+                if self.table.is_some() {
+                    self.write(&code);
+                } else {
+                    self.write(&format!("`{}`", escape_code(&code)));
+                }
+            }
+            Event::InlineMath(math) | Event::DisplayMath(math) => self.text(&math),
+            Event::Html(html) | Event::InlineHtml(html) => self.text(&html),
+            Event::FootnoteReference(label) => self.text(&format!("[{label}]")),
+            Event::SoftBreak | Event::HardBreak => self.line_break(),
+            Event::Rule => {
+                self.request_gap(Gap::Paragraph);
+                self.write("———");
+                self.request_gap(Gap::Paragraph);
+            }
+            Event::TaskListMarker(checked) => self.write(if checked { "☑ " } else { "☐ " }),
+        }
+    }
+}
+
+/// Converts CommonMark, as written by the model, to Telegram's MarkdownV2.
+///
+/// Headings become bold lines and tables become monospace blocks.
+pub fn to_markdown_v2(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut renderer = Renderer::default();
+    for event in Parser::new_ext(markdown, options) {
+        renderer.event(event);
+    }
+    renderer.out.trim_end().to_string()
+}
+
+/// Telegram's limit on the length of a message
+pub const MAX_MESSAGE_LENGTH: usize = 4096;
+
+/// Length as counted by Telegram, in UTF-16 code units
+fn telegram_length(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// The source ranges of top-level blocks: paragraphs, code blocks, lists and so on
+fn top_level_blocks(markdown: &str) -> Vec<std::ops::Range<usize>> {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut blocks = Vec::new();
+    let mut depth = 0usize;
+    for (event, range) in Parser::new_ext(markdown, options).into_offset_iter() {
+        match event {
+            Event::Start(_) => {
+                if depth == 0 {
+                    blocks.push(range);
+                }
+                depth += 1;
+            }
+            Event::End(_) => depth = depth.saturating_sub(1),
+            _ if depth == 0 => blocks.push(range),
+            _ => (),
+        }
+    }
+    blocks
+}
+
+/// Cuts `text` into pieces of `chars` characters. Escaping at most doubles the length, so
+/// `chars = limit / 2` always fits.
+fn hard_split(text: &str, chars: usize) -> Vec<String> {
+    text.chars()
+        .collect::<Vec<_>>()
+        .chunks(chars.max(1))
+        .map(|chunk| chunk.iter().collect())
+        .collect()
+}
+
+/// Greedily joins `pieces` into as few chunks as `fits` allows, splitting oversized pieces further.
+fn pack(pieces: Vec<&str>, fits: &dyn Fn(&str) -> bool, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for piece in pieces {
+        let candidate = format!("{current}{piece}");
+        if fits(candidate.trim_end()) {
+            current = candidate;
+            continue;
+        }
+        if !current.trim().is_empty() {
+            chunks.push(std::mem::take(&mut current).trim_end().to_string());
+        }
+        current.clear();
+        if fits(piece.trim_end()) {
+            current = piece.to_string();
+        } else {
+            chunks.extend(split_text(piece, fits, limit));
+        }
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current.trim_end().to_string());
+    }
+    chunks
+}
+
+/// Splits text at line breaks, then at spaces, then anywhere.
+fn split_text(text: &str, fits: &dyn Fn(&str) -> bool, limit: usize) -> Vec<String> {
+    if fits(text.trim_end()) {
+        return vec![text.trim_end().to_string()];
+    }
+    for separator in ["\n", " "] {
+        let pieces = text.split_inclusive(separator).collect::<Vec<_>>();
+        if pieces.len() > 1 {
+            return pack(pieces, fits, limit);
+        }
+    }
+    hard_split(text, limit / 2)
+}
+
+/// Splits a block that doesn't fit in one message. Code blocks are split between lines, and
+/// every piece gets its own fences.
+fn split_block(block: &str, limit: usize) -> Vec<String> {
+    let fence = ["```", "~~~"].into_iter().find(|fence| block.trim_start().starts_with(fence));
+    if let Some(fence) = fence {
+        let block = block.trim();
+        let (header, body) = block.split_once('\n').unwrap_or((block, ""));
+        let body = body.trim_end().strip_suffix(fence).unwrap_or(body);
+        let wrap = |code: &str| format!("{}\n{}\n{}", header, code.trim_end_matches('\n'), fence);
+        let fits = |code: &str| telegram_length(&to_markdown_v2(&wrap(code))) <= limit;
+        // reserve room for the fences when hard-splitting a single long line
+        let pieces = split_text(body, &fits, limit.saturating_sub(header.len() + 16));
+        return pieces.iter().map(|code| wrap(code)).collect();
+    }
+    let fits = |text: &str| telegram_length(&to_markdown_v2(text)) <= limit;
+    split_text(block, &fits, limit)
+}
+
+/// Splits Markdown into parts whose MarkdownV2 rendering fits in one message, breaking between
+/// paragraphs and code blocks where possible. Every part is valid Markdown on its own, so the
+/// formatting of each message is balanced.
+pub fn split_markdown(markdown: &str, limit: usize) -> Vec<String> {
+    let fits = |text: &str| telegram_length(&to_markdown_v2(text)) <= limit;
+    if fits(markdown) {
+        return vec![markdown.to_string()];
+    }
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    for range in top_level_blocks(markdown) {
+        let block = markdown[range].trim_end();
+        let candidate = if current.is_empty() { block.to_string() } else { format!("{current}\n\n{block}") };
+        if fits(&candidate) {
+            current = candidate;
+            continue;
+        }
+        if !current.is_empty() {
+            parts.push(std::mem::take(&mut current));
+        }
+        if fits(block) {
+            current = block.to_string();
+        } else {
+            parts.extend(split_block(block, limit));
+        }
+    }
+    if !current.is_empty() {
+        parts.push(current);
+    }
+    parts
+}
+
+/// A fenced or indented code block; `language` is the first word of the fence's info string
+pub struct CodeBlock {
+    pub language: String,
+    pub code: String,
+}
+
+pub fn code_blocks(markdown: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut current: Option<CodeBlock> = None;
+    for event in Parser::new_ext(markdown, Options::empty()) {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let language = match kind {
+                    CodeBlockKind::Fenced(info) => info.split_whitespace().next().unwrap_or_default().to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                current = Some(CodeBlock { language, code: String::new() });
+            }
+            Event::Text(text) => {
+                if let Some(block) = &mut current {
+                    block.code.push_str(&text);
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => blocks.extend(current.take()),
+            _ => (),
+        }
+    }
+    blocks
+}
+
+/// File extension for code written in `language`, as named in a fence's info string
+pub fn file_extension(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" | "python3" => "py",
+        "javascript" | "js" | "node" => "js",
+        "typescript" | "ts" => "ts",
+        "jsx" => "jsx",
+        "tsx" => "tsx",
+        "c" => "c",
+        "cpp" | "c++" | "cxx" | "cc" => "cpp",
+        "h" => "h",
+        "hpp" => "hpp",
+        "csharp" | "cs" | "c#" => "cs",
+        "java" => "java",
+        "kotlin" | "kt" => "kt",
+        "scala" => "scala",
+        "swift" => "swift",
+        "go" | "golang" => "go",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "perl" | "pl" => "pl",
+        "lua" => "lua",
+        "r" => "r",
+        "haskell" | "hs" => "hs",
+        "ocaml" | "ml" => "ml",
+        "elixir" | "ex" => "ex",
+        "erlang" | "erl" => "erl",
+        "clojure" | "clj" => "clj",
+        "dart" => "dart",
+        "zig" => "zig",
+        "nix" => "nix",
+        "bash" | "sh" | "shell" | "zsh" | "console" => "sh",
+        "powershell" | "ps1" => "ps1",
+        "bat" | "batch" | "cmd" => "bat",
+        "sql" => "sql",
+        "html" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "xml" => "xml",
+        "json" => "json",
+        "yaml" | "yml" => "yml",
+        "toml" => "toml",
+        "ini" => "ini",
+        "markdown" | "md" => "md",
+        "latex" | "tex" => "tex",
+        "dockerfile" | "docker" => "dockerfile",
+        "makefile" | "make" => "mk",
+        "diff" | "patch" => "diff",
+        _ => "txt",
+    }
+}
+
+/// Closes a code block left open by cutting `markdown` short, so that nothing appended to it ends up inside
+fn close_fence(mut markdown: String) -> String {
+    let mut open: Option<(String, String)> = None;
+    for line in markdown.lines() {
+        let trimmed = line.trim_start();
+        let indent = &line[..line.len() - trimmed.len()];
+        let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~');
+        let fence = marker.map(|marker| trimmed.chars().take_while(|c| *c == marker).collect::<String>()).filter(|fence| fence.len() >= 3);
+        match (&open, fence) {
+            (None, Some(fence)) => open = Some((indent.to_string(), fence)),
+            (Some((_, opening)), Some(fence)) if fence.starts_with(opening.as_str()) && trimmed[fence.len()..].trim().is_empty() => open = None,
+            _ => (),
+        }
+    }
+    if let Some((indent, fence)) = open {
+        markdown.push_str(&format!("\n{indent}{fence}"));
+    }
+    markdown
+}
+
+/// The leading blocks of `markdown`, up to roughly `max_chars` characters
+pub fn summary(markdown: &str, max_chars: usize) -> String {
+    let mut ret = String::new();
+    for range in top_level_blocks(markdown) {
+        let block = markdown[range].trim_end();
+        if ret.chars().count() + block.chars().count() > max_chars {
+            if ret.is_empty() {
+                ret = close_fence(format!("{}…", block.chars().take(max_chars).collect::<String>()));
+            }
+            break;
+        }
+        if !ret.is_empty() {
+            ret.push_str("\n\n");
+        }
+        ret.push_str(block);
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_special_characters_in_plain_text() {
+        assert_eq!(to_markdown_v2("a.b! (x) [y] 1+1=2 #tag"), r"a\.b\! \(x\) \[y\] 1\+1\=2 \#tag");
+        assert_eq!(escape("_*~|"), r"\_\*\~\|");
+    }
+
+    #[test]
+    fn renders_nested_entities() {
+        assert_eq!(to_markdown_v2("**bold _italic_** ~~gone~~"), "*bold _italic_* ~gone~");
+        assert_eq!(to_markdown_v2("***both***"), "_*both*_");
+        assert_eq!(to_markdown_v2("# Head *x*"), "*Head _x_*");
+        assert_eq!(to_markdown_v2("> quote **b**"), ">quote *b*");
+    }
+
+    #[test]
+    fn escapes_code_and_links() {
+        assert_eq!(to_markdown_v2(r"`a\b`x"), r"`a\\b`x");
+        assert_eq!(to_markdown_v2("```rust\nlet a = `b`;\n```"), "```rust\nlet a = \\`b\\`;\n```");
+        assert_eq!(to_markdown_v2("[li_nk](https://e.com/a_(b))"), r"[li\_nk](https://e.com/a_(b\))");
+    }
+
+    #[test]
+    fn renders_lists_and_tables() {
+        assert_eq!(to_markdown_v2("- one\n- two\n\n1. a\n2. b"), "• one\n• two\n\n1\\. a\n2\\. b");
+        assert_eq!(to_markdown_v2("|a|b|\n|-|-|\n|1|2|"), "```\na | b\n--+--\n1 | 2\n```");
+    }
+
+    #[test]
+    fn keeps_short_text_in_one_part() {
+        assert_eq!(split_markdown("one\n\ntwo", MAX_MESSAGE_LENGTH), vec!["one\n\ntwo"]);
+    }
+
+    #[test]
+    fn splits_between_paragraphs() {
+        let paragraph = "word ".repeat(150);
+        let text = [paragraph.trim_end(); 3].join("\n\n");
+        let parts = split_markdown(&text, 1000);
+        assert_eq!(parts, vec![paragraph.trim_end(); 3]);
+    }
+
+    #[test]
+    fn splits_code_blocks_between_lines_with_fences() {
+        let lines = (0..300).map(|i| format!("print({i})  # some padding\n")).collect::<String>();
+        let text = format!("intro\n\n```py\n{lines}```\n\nend");
+        let parts = split_markdown(&text, 1000);
+        assert_eq!(parts.first().map(String::as_str), Some("intro"));
+        assert_eq!(parts.last().map(String::as_str), Some("end"));
+        let code = &parts[1..parts.len() - 1];
+        assert!(code.len() > 1);
+        let mut joined = String::new();
+        for part in code {
+            assert!(telegram_length(&to_markdown_v2(part)) <= 1000);
+            let body = part.strip_prefix("```py\n").and_then(|part| part.strip_suffix("\n```")).expect("every part is fenced");
+            joined.push_str(body);
+            joined.push('\n');
+        }
+        assert_eq!(joined, lines);
+    }
+
+    #[test]
+    fn summary_closes_cut_code_blocks() {
+        let code = format!("```rust\n{}```", "let x = 1;\n".repeat(20));
+        assert_eq!(summary(&code, 30), "```rust\nlet x = 1;\nlet x = 1;\n…\n```");
+        assert_eq!(summary(&format!("- item\n\n  ~~~~\n{}  ~~~~", "  code\n".repeat(20)), 20), "- item\n\n  ~~~~\n  cod…\n  ~~~~");
+        assert_eq!(summary("para one\n\npara two", 12), "para one");
+    }
+
+    #[test]
+    fn hard_splits_long_lines() {
+        let text = "x".repeat(5000);
+        let parts = split_markdown(&text, MAX_MESSAGE_LENGTH);
+        assert!(parts.iter().all(|part| telegram_length(&to_markdown_v2(part)) <= MAX_MESSAGE_LENGTH));
+        assert_eq!(parts.concat(), text);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::settings::{ModelPreference, Preferences, SearchPreference};
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
+
+/// Callback data of the settings menu starts with this
+pub const CALLBACK_PREFIX: &str = "settings:";
+const MAX_TOKENS_CHOICES: [u64; 4] = [300, 1000, 2000, 4000];
+/// Languages answers can be written in, as shown and as told to the model
+const LANGUAGES: [(&str, &str); 7] = [
+    ("English", "English"),
+    ("中文", "Chinese"),
+    ("Español", "Spanish"),
+    ("Français", "French"),
+    ("Deutsch", "German"),
+    ("Русский", "Russian"),
+    ("日本語", "Japanese"),
+];
+
+fn button(label: impl Into<String>, action: &str) -> InlineKeyboardButton {
+    InlineKeyboardButton::callback(label, format!("{CALLBACK_PREFIX}{action}"))
+}
+
+fn model_label(model: Option<ModelPreference>) -> &'static str {
+    match model {
+        Some(ModelPreference::Reasoner) => "deepseek-reasoner",
+        _ => "deepseek-chat",
+    }
+}
+
+fn search_label(search: Option<SearchPreference>) -> &'static str {
+    match search {
+        Some(SearchPreference::Always) => "always",
+        Some(SearchPreference::Never) => "never",
+        _ => "when useful",
+    }
+}
+
+fn reasoning_label(show_reasoning: Option<bool>) -> &'static str {
+    if show_reasoning.unwrap_or_default() { "shown" } else { "hidden" }
+}
+
+fn language_label(language: &Option<String>) -> String {
+    match language {
+        Some(language) => LANGUAGES.iter()
+            .find(|(_, name)| name == language)
+            .map(|(label, _)| label.to_string())
+            .unwrap_or_else(|| language.to_owned()),
+        None => String::from("same as the question"),
+    }
+}
+
+/// The current preferences, one per line, marking those left to the default
+pub fn describe(title: &str, preferences: &Preferences, default_max_tokens: u64) -> String {
+    let default = |set: bool| if set { "" } else { " (default)" };
+    format!(
+        "{}\n\nModel: {}{}\nMax tokens: {}{}\nWeb search: {}{}\nReasoning: {}{}\nLanguage: {}{}",
+        title,
+        model_label(preferences.model), default(preferences.model.is_some()),
+        preferences.max_tokens.unwrap_or(default_max_tokens), default(preferences.max_tokens.is_some()),
+        search_label(preferences.search), default(preferences.search.is_some()),
+        reasoning_label(preferences.show_reasoning), default(preferences.show_reasoning.is_some()),
+        language_label(&preferences.language), default(preferences.language.is_some()),
+    )
+}
+
+pub fn main_menu(preferences: &Preferences, default_max_tokens: u64) -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new(vec![
+        vec![button(format!("Model: {}", model_label(preferences.model)), "model")],
+        vec![button(format!("Max tokens: {}", preferences.max_tokens.unwrap_or(default_max_tokens)), "tokens")],
+        vec![button(format!("Web search: {}", search_label(preferences.search)), "search")],
+        vec![button(format!("Reasoning: {}", reasoning_label(preferences.show_reasoning)), "reasoning")],
+        vec![button(format!("Language: {}", language_label(&preferences.language)), "language")],
+        vec![button("Reset", "reset"), button("Close", "close")],
+    ])
+}
+
+/// The choices for one preference, or `None` if there is no such preference
+pub fn submenu(field: &str) -> Option<InlineKeyboardMarkup> {
+    let choices = match field {
+        "model" => vec![
+            button(model_label(Some(ModelPreference::Chat)), "model:chat"),
+            button(model_label(Some(ModelPreference::Reasoner)), "model:reasoner"),
+        ],
+        "tokens" => MAX_TOKENS_CHOICES.iter()
+            .map(|tokens| button(tokens.to_string(), &format!("tokens:{tokens}")))
+            .collect(),
+        "search" => vec![
+            button(search_label(Some(SearchPreference::Auto)), "search:auto"),
+            button(search_label(Some(SearchPreference::Always)), "search:always"),
+            button(search_label(Some(SearchPreference::Never)), "search:never"),
+        ],
+        "reasoning" => vec![
+            button(reasoning_label(Some(true)), "reasoning:show"),
+            button(reasoning_label(Some(false)), "reasoning:hide"),
+        ],
+        "language" => LANGUAGES.iter()
+            .map(|(label, name)| button(*label, &format!("language:{name}")))
+            .collect(),
+        _ => return None,
+    };
+    let mut rows = choices.chunks(3).map(|row| row.to_vec()).collect::<Vec<_>>();
+    rows.push(vec![button("Default", &format!("{field}:default")), button("« Back", "menu")]);
+    Some(InlineKeyboardMarkup::new(rows))
+}
+
+/// Sets the preference `field` to `value`, or back to the default for "default". Returns `false` for
+/// anything the menu doesn't offer.
+pub fn apply(preferences: &mut Preferences, field: &str, value: &str) -> bool {
+    let reset = value == "default";
+    match field {
+        "model" => preferences.model = match value {
+            "chat" => Some(ModelPreference::Chat),
+            "reasoner" => Some(ModelPreference::Reasoner),
+            _ if reset => None,
+            _ => return false,
+        },
+        "tokens" => preferences.max_tokens = match value.parse::<u64>() {
+            Ok(tokens) if MAX_TOKENS_CHOICES.contains(&tokens) => Some(tokens),
+            _ if reset => None,
+            _ => return false,
+        },
+        "search" => preferences.search = match value {
+            "auto" => Some(SearchPreference::Auto),
+            "always" => Some(SearchPreference::Always),
+            "never" => Some(SearchPreference::Never),
+            _ if reset => None,
+            _ => return false,
+        },
+        "reasoning" => preferences.show_reasoning = match value {
+            "show" => Some(true),
+            "hide" => Some(false),
+            _ if reset => None,
+            _ => return false,
+        },
+        "language" => preferences.language = match LANGUAGES.iter().find(|(_, name)| *name == value) {
+            Some((_, name)) => Some(name.to_string()),
+            None if reset => None,
+            None => return false,
+        },
+        _ => return false,
+    }
+    true
+}
@@ -0,0 +1,67 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many previews are kept before the oldest ones are forgotten
+const MAX_PREVIEWS: usize = 500;
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// A user and what they typed
+type PreviewKey = (u64, String);
+
+/// Quick answers shown in inline results, by user and query, along with each user's latest inline query so
+/// that queries typed over can be dropped
+#[derive(Default)]
+pub struct PreviewCache {
+    latest: Mutex<HashMap<u64, String>>,
+    previews: Mutex<(HashMap<PreviewKey, String>, VecDeque<PreviewKey>)>,
+    /// when each user's previews in the last hour were asked for
+    asked: Mutex<HashMap<u64, VecDeque<Instant>>>,
+}
+
+impl PreviewCache {
+    /// Records `query_id` as the user's latest inline query
+    pub fn begin(&self, user: u64, query_id: &str) {
+        self.latest.lock().unwrap().insert(user, query_id.to_string());
+    }
+    /// Whether the user hasn't sent another inline query since `query_id`
+    pub fn is_latest(&self, user: u64, query_id: &str) -> bool {
+        self.latest.lock().unwrap().get(&user).is_some_and(|latest| latest == query_id)
+    }
+    /// Counts a preview asked for by the user, unless they already had `hourly_limit` in the last hour
+    pub fn allow(&self, user: u64, hourly_limit: usize) -> bool {
+        if hourly_limit == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut asked = self.asked.lock().unwrap();
+        asked.retain(|_, times| {
+            while times.front().is_some_and(|time| now.duration_since(*time) >= HOUR) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = asked.entry(user).or_default();
+        if times.len() >= hourly_limit {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+    pub fn get(&self, user: u64, query: &str) -> Option<String> {
+        self.previews.lock().unwrap().0.get(&(user, query.trim().to_string())).cloned()
+    }
+    pub fn insert(&self, user: u64, query: &str, preview: String) {
+        let key = (user, query.trim().to_string());
+        let mut guard = self.previews.lock().unwrap();
+        let (previews, order) = &mut *guard;
+        if previews.insert(key.to_owned(), preview).is_none() {
+            order.push_back(key);
+        }
+        while order.len() > MAX_PREVIEWS {
+            if let Some(oldest) = order.pop_front() {
+                previews.remove(&oldest);
+            }
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::Mutex;
+
+const CHAT_SETTINGS_PATH: &str = "chatsettings.toml";
+
+/// Serializes updates of the chat settings, so that concurrent ones don't overwrite each other
+static SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatSettings {
+    /// whether the bot answers in this chat at all
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// system prompt set with /system, takes precedence over `persona`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    /// name of the configured persona chosen with /persona
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// answer preferences chosen with /settings
+    #[serde(default)]
+    pub preferences: Preferences,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        Self { enabled: default_enabled(), system_prompt: None, persona: None, preferences: Preferences::default() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelPreference {
+    Chat,
+    Reasoner,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchPreference {
+    Auto,
+    Always,
+    Never,
+}
+
+/// How answers are produced. Unset preferences fall back to the bot's defaults.
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Preferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<ModelPreference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search: Option<SearchPreference>,
+    /// whether deepseek-reasoner's chain of thought is shown before the answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub show_reasoning: Option<bool>,
+    /// language answers are written in, rather than the question's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+}
+
+impl Preferences {
+    /// `self` with the preferences set in `other` taking precedence
+    pub fn overlay(self, other: Preferences) -> Preferences {
+        Preferences {
+            model: other.model.or(self.model),
+            max_tokens: other.max_tokens.or(self.max_tokens),
+            search: other.search.or(self.search),
+            show_reasoning: other.show_reasoning.or(self.show_reasoning),
+            language: other.language.or(self.language),
+        }
+    }
+    pub fn model(&self) -> deepseek::types::DeepSeekModel {
+        match self.model {
+            Some(ModelPreference::Reasoner) => deepseek::types::DeepSeekModel::DeepSeekReasoner,
+            _ => deepseek::types::DeepSeekModel::DeepSeekChat,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct AllChatSettings {
+    #[serde(default)]
+    pub chats: HashMap<String, ChatSettings>,
+}
+
+pub fn get_all_chat_settings() -> Result<AllChatSettings, Box<dyn std::error::Error + Send + Sync>> {
+    match std::fs::read_to_string(CHAT_SETTINGS_PATH) {
+        Ok(content) => Ok(toml::from_str::<AllChatSettings>(content.as_str())?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AllChatSettings::default()),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+/// Writes through a temporary file, so that readers never see it half-written
+fn set_all_chat_settings(settings: AllChatSettings) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let temp = format!("{CHAT_SETTINGS_PATH}.tmp");
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(toml::to_string(&settings)?.as_bytes())?;
+    std::fs::rename(temp, CHAT_SETTINGS_PATH)?;
+    Ok(())
+}
+
+pub fn get_chat_settings(chat_id: String) -> Result<ChatSettings, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(get_all_chat_settings()?.chats.remove(&chat_id).unwrap_or_default())
+}
+
+pub fn update_chat_settings(chat_id: String, update: impl FnOnce(&mut ChatSettings)) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _guard = SETTINGS_LOCK.lock().unwrap();
+    let mut settings = get_all_chat_settings()?;
+    update(settings.chats.entry(chat_id).or_default());
+    set_all_chat_settings(settings)?;
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+struct Task {
+    owner: u64,
+    chat_id: Option<i64>,
+    cancel: Arc<Notify>,
+}
+
+/// Requests to DeepSeek that are in flight, so that they can be stopped
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: Mutex<HashMap<String, Task>>,
+    next_id: AtomicU64,
+}
+
+/// A registered request. It is unregistered when this is dropped.
+pub struct TaskHandle {
+    registry: Arc<TaskRegistry>,
+    pub id: String,
+    /// notified when the request is to be stopped
+    pub cancel: Arc<Notify>,
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        self.registry.tasks.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl TaskRegistry {
+    /// Registers a request by user `owner`, in the chat `chat_id` unless it comes from an inline message
+    pub fn start(self: &Arc<Self>, owner: u64, chat_id: Option<i64>) -> TaskHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        let cancel = Arc::new(Notify::new());
+        self.tasks.lock().unwrap().insert(id.to_owned(), Task { owner, chat_id, cancel: cancel.clone() });
+        TaskHandle { registry: self.clone(), id, cancel }
+    }
+    /// Stops the request `id` if `user` started it, or regardless with `any_owner`. Returns whether it was stopped.
+    pub fn cancel(&self, id: &str, user: u64, any_owner: bool) -> bool {
+        match self.tasks.lock().unwrap().get(id) {
+            Some(task) if any_owner || task.owner == user => {
+                // stores a permit, so a request between two steps stops at the next one
+                task.cancel.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+    /// Stops every request `user` started in the chat, returning how many there were
+    pub fn cancel_all(&self, chat_id: i64, user: u64) -> usize {
+        let tasks = self.tasks.lock().unwrap();
+        let mine = tasks.values().filter(|task| task.owner == user && task.chat_id == Some(chat_id)).collect::<Vec<_>>();
+        for task in &mine {
+            task.cancel.notify_one();
+        }
+        mine.len()
+    }
+}
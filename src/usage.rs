@@ -0,0 +1,285 @@
+use serde::Serialize;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::sync::Mutex;
+
+const USAGE_PATH: &str = "usage.jsonl";
+const DAY: u64 = 60 * 60 * 24;
+/// Users beyond this many are left out of the leaderboard
+const TOP_USERS: usize = 10;
+const CHART_WIDTH: usize = 640;
+const CHART_HEIGHT: usize = 320;
+const CHART_MARGIN: usize = 20;
+
+/// Serializes appending to the usage log
+static USAGE_LOCK: Mutex<()> = Mutex::new(());
+
+/// USD per million tokens of one model
+#[derive(Deserialize, Clone, Copy)]
+pub struct ModelPricing {
+    pub input_cache_hit: f64,
+    pub input_cache_miss: f64,
+    pub output: f64,
+}
+
+/// Prices used to estimate costs, e.g. `[pricing]` in the bot config
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct PricingConfig {
+    pub chat: ModelPricing,
+    pub reasoner: ModelPricing,
+}
+
+impl Default for PricingConfig {
+    fn default() -> Self {
+        Self {
+            chat: ModelPricing { input_cache_hit: 0.07, input_cache_miss: 0.27, output: 1.10 },
+            reasoner: ModelPricing { input_cache_hit: 0.14, input_cache_miss: 0.55, output: 2.19 },
+        }
+    }
+}
+
+impl PricingConfig {
+    /// Estimated cost of a completion in USD
+    pub fn cost(&self, record: &UsageRecord) -> f64 {
+        let pricing = if record.model == deepseek::types::DeepSeekModel::DeepSeekReasoner.name() { self.reasoner } else { self.chat };
+        let cache_miss_tokens = record.prompt_tokens.saturating_sub(record.cache_hit_tokens);
+        (record.cache_hit_tokens as f64 * pricing.input_cache_hit
+            + cache_miss_tokens as f64 * pricing.input_cache_miss
+            + record.completion_tokens as f64 * pricing.output) / 1_000_000.0
+    }
+}
+
+/// One completion made for a user
+#[derive(Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// Unix timestamp
+    pub time: u64,
+    pub user: u64,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cache_hit_tokens: u64,
+    /// whether the answer was given search results
+    pub searched: bool,
+    /// made while searching, to decide whether to search, what for, or to summarize a result, rather than to answer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub searching: bool,
+    /// milliseconds from asking to the whole completion, waiting in the queue included
+    pub latency: u64,
+    /// name of the API key that paid for it
+    #[serde(default)]
+    pub key: String,
+}
+
+pub fn record(record: &UsageRecord) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let _guard = USAGE_LOCK.lock().unwrap();
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(USAGE_PATH)?;
+    writeln!(file, "{}", serde_json::to_string(record)?)?;
+    Ok(())
+}
+
+/// Records since the Unix timestamp `since`, skipping lines that can't be read
+pub fn records_since(since: u64) -> Result<Vec<UsageRecord>, Box<dyn std::error::Error + Send + Sync>> {
+    let content = match std::fs::read_to_string(USAGE_PATH) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(Box::new(e)),
+    };
+    Ok(content.lines()
+        .filter_map(|line| serde_json::from_str::<UsageRecord>(line).ok())
+        .filter(|record| record.time >= since)
+        .collect())
+}
+
+/// Start of the day `now` falls in, in UTC
+pub fn day_start(now: u64) -> u64 {
+    now - now % DAY
+}
+
+/// Start of the month `now` falls in, in UTC
+pub fn month_start(now: u64) -> u64 {
+    let days = now / DAY;
+    (days + 1 - day_of_month(days)) * DAY
+}
+
+/// ref: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn day_of_month(days: u64) -> u64 {
+    let z = days + 719468;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    doy - (153 * mp + 2) / 5 + 1
+}
+
+#[derive(Default)]
+pub struct Totals {
+    pub requests: u64,
+    pub tokens: u64,
+    pub cost: f64,
+}
+
+impl Totals {
+    fn add(&mut self, record: &UsageRecord, pricing: &PricingConfig) {
+        self.requests += 1;
+        self.tokens += record.prompt_tokens + record.completion_tokens;
+        self.cost += pricing.cost(record);
+    }
+}
+
+impl std::fmt::Display for Totals {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} requests, {} tokens, ${:.4}", self.requests, self.tokens, self.cost)
+    }
+}
+
+pub fn totals<'a>(records: impl IntoIterator<Item = &'a UsageRecord>, pricing: &PricingConfig) -> Totals {
+    let mut totals = Totals::default();
+    for record in records {
+        totals.add(record, pricing);
+    }
+    totals
+}
+
+/// Totals, rates, and breakdowns by model and by user, for superusers
+pub fn report(records: &[UsageRecord], pricing: &PricingConfig) -> String {
+    let answers = records.iter().filter(|record| !record.searching).collect::<Vec<_>>();
+    let count = answers.len().max(1) as f64;
+    let searched = answers.iter().filter(|record| record.searched).count() as f64;
+    let prompt_tokens = records.iter().map(|record| record.prompt_tokens).sum::<u64>();
+    let cache_hit_tokens = records.iter().map(|record| record.cache_hit_tokens).sum::<u64>();
+    let latency = answers.iter().map(|record| record.latency).sum::<u64>() as f64 / count / 1000.0;
+    let mut ret = format!("Total: {}\n", totals(records, pricing));
+    ret.push_str(&format!("Searching: {}\n", totals(records.iter().filter(|record| record.searching), pricing)));
+    ret.push_str(&format!("Web search: {:.0}% of answer requests\n", searched / count * 100.0));
+    ret.push_str(&format!("DeepSeek prompt cache hits: {:.0}% of prompt tokens\n", cache_hit_tokens as f64 / prompt_tokens.max(1) as f64 * 100.0));
+    ret.push_str(&format!("Average answer request time: {:.1}s\n", latency));
+    let mut models = HashMap::<&str, Totals>::new();
+    let mut users = HashMap::<u64, Totals>::new();
+    for record in records {
+        models.entry(record.model.as_str()).or_default().add(record, pricing);
+        users.entry(record.user).or_default().add(record, pricing);
+    }
+    let mut models = models.into_iter().collect::<Vec<_>>();
+    models.sort_by(|a, b| a.0.cmp(b.0));
+    ret.push_str("\nBy model:\n");
+    for (model, totals) in models {
+        ret.push_str(&format!("{}: {}\n", model, totals));
+    }
+    let mut users = users.into_iter().collect::<Vec<_>>();
+    users.sort_by(|a, b| b.1.cost.total_cmp(&a.1.cost));
+    ret.push_str("\nTop users:\n");
+    for (i, (user, totals)) in users.into_iter().take(TOP_USERS).enumerate() {
+        ret.push_str(&format!("{}. {}: {}\n", i + 1, user, totals));
+    }
+    ret
+}
+
+/// A bar chart of the cost of each day from `since` to `now`, as a PNG
+pub fn daily_cost_chart(records: &[UsageRecord], pricing: &PricingConfig, since: u64, now: u64) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+    let days = ((now - day_start(since)) / DAY + 1) as usize;
+    let mut costs = vec![0.0; days];
+    for record in records.iter().filter(|record| record.time >= since && record.time <= now) {
+        costs[((record.time - day_start(since)) / DAY) as usize] += pricing.cost(record);
+    }
+    let highest = costs.iter().cloned().fold(0.0, f64::max);
+    // white background, blue bars, grey baseline
+    let mut pixels = vec![255u8; CHART_WIDTH * CHART_HEIGHT * 3];
+    let mut fill = |x: usize, y: usize, color: [u8; 3]| {
+        let offset = (y * CHART_WIDTH + x) * 3;
+        pixels[offset..offset + 3].copy_from_slice(&color);
+    };
+    let plot_width = CHART_WIDTH - 2 * CHART_MARGIN;
+    let plot_height = CHART_HEIGHT - 2 * CHART_MARGIN;
+    let slot = plot_width / days.max(1);
+    for (day, cost) in costs.iter().enumerate() {
+        let height = if highest > 0.0 { (cost / highest * plot_height as f64).round() as usize } else { 0 };
+        let left = CHART_MARGIN + day * slot + slot / 8;
+        let right = CHART_MARGIN + (day + 1) * slot - slot / 8;
+        for x in left..right.max(left + 1) {
+            for y in CHART_HEIGHT - CHART_MARGIN - height..CHART_HEIGHT - CHART_MARGIN {
+                fill(x, y, [66, 103, 210]);
+            }
+        }
+    }
+    for x in CHART_MARGIN..CHART_WIDTH - CHART_MARGIN {
+        fill(x, CHART_HEIGHT - CHART_MARGIN, [128, 128, 128]);
+    }
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, CHART_WIDTH as u32, CHART_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: u64, user: u64, completion_tokens: u64, searched: bool, searching: bool) -> UsageRecord {
+        UsageRecord {
+            time,
+            user,
+            model: String::from("deepseek-chat"),
+            prompt_tokens: 1000,
+            completion_tokens,
+            cache_hit_tokens: 250,
+            searched,
+            searching,
+            latency: 2000,
+            key: String::from(crate::keys::DEFAULT_KEY),
+        }
+    }
+
+    #[test]
+    fn months_start_on_their_first_day() {
+        // 2024-02-29 12:00, a leap day
+        assert_eq!(day_of_month(1709208000 / DAY), 29);
+        assert_eq!(month_start(1709208000), 1706745600);
+        // 2025-01-01 00:00
+        assert_eq!(day_of_month(1735689600 / DAY), 1);
+        assert_eq!(month_start(1735689600), 1735689600);
+        // 2023-12-31 23:59:59
+        assert_eq!(day_of_month(1704067199 / DAY), 31);
+        assert_eq!(month_start(1704067199), 1701388800);
+    }
+
+    #[test]
+    fn report_tells_answers_from_searching() {
+        let records = vec![
+            record(0, 1, 1000, true, false),
+            record(0, 1, 1000, false, true),
+            record(0, 2, 4000, false, false),
+        ];
+        let report = report(&records, &PricingConfig::default());
+        assert!(report.contains("Total: 3 requests, 9000 tokens"), "{report}");
+        assert!(report.contains("Searching: 1 requests, 2000 tokens"), "{report}");
+        assert!(report.contains("Web search: 50% of answer requests"), "{report}");
+        assert!(report.contains("DeepSeek prompt cache hits: 25% of prompt tokens"), "{report}");
+        assert!(report.contains("Average answer request time: 2.0s"), "{report}");
+        // user 2 spent the most on output
+        let top = report.split("Top users:\n").nth(1).unwrap();
+        assert!(top.starts_with("1. 2: "), "{report}");
+        assert!(top.contains("2. 1: 2 requests"), "{report}");
+    }
+
+    #[test]
+    fn chart_has_a_bar_per_day() {
+        let pricing = PricingConfig { chat: ModelPricing { input_cache_hit: 0.0, input_cache_miss: 0.0, output: 1.0 }, ..PricingConfig::default() };
+        let records = vec![record(10, 1, 1000, false, false), record(2 * DAY + 10, 1, 2000, false, false)];
+        let png = daily_cost_chart(&records, &pricing, 0, 2 * DAY + 20).unwrap();
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        let pixel = |x: usize, y: usize| pixels[(y * CHART_WIDTH + x) * 3..(y * CHART_WIDTH + x) * 3 + 3].to_vec();
+        let (blue, white) = (vec![66, 103, 210], vec![255, 255, 255]);
+        // the third day cost twice as much as the first, and the second nothing
+        assert_eq!(pixel(500, 30), blue);
+        assert_eq!(pixel(100, 250), blue);
+        assert_eq!(pixel(100, 100), white);
+        assert_eq!(pixel(300, 290), white);
+    }
+}
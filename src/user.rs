@@ -7,10 +7,11 @@ pub enum Role {
 
 pub fn check_uid(uid: String) -> Result<Role, Box<dyn std::error::Error + Send + Sync>> {
     let config = config::get_config()?;
-    if uid == config.superuser_uid {
+    if config.superusers().contains(&uid) {
         return Ok(Role::SuperUser);
     }
-    if config::get_trusted_users()?.trusted_users.into_iter().find(|x| *x == uid) != None {
+    // users whose access has expired are left out here, and removed from the file by config::prune_trusted_users
+    if config::get_trusted_users()?.trusted_users.contains(&uid) {
         return Ok(Role::User);
     }
     Ok(Role::Untrusted)